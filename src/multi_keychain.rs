@@ -1,13 +1,23 @@
 //! Module containing the multi-keychain [`Wallet`].
 
 mod changeset;
+pub mod fee;
 pub mod keyring;
+mod labels;
+mod persist;
+#[cfg(test)]
+mod test_utils;
 mod wallet;
 pub mod errors;
 mod tx_builder;
 
 pub use changeset::*;
-pub use keyring::KeyRing;
+pub use keyring::{KeyRing, KeyRingBuilder, KeyRingDiff};
+pub use labels::Labels;
+pub use persist::WalletPersister;
+#[cfg(feature = "async")]
+pub use persist::{AsyncWalletPersister, FutureResult};
+pub use tx_builder::{ChangePolicy, TxBatch};
 pub use wallet::*;
 
 /// Alias for [`DescriptorId`](bdk_chain::DescriptorId).
@@ -1,13 +1,23 @@
 use bdk_chain::{
     indexed_tx_graph, keychain_txout, local_chain, tx_graph, ConfirmationBlockTime, Merge,
 };
+#[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
 use crate::bdk_chain;
-use crate::multi_keychain::keyring;
+use crate::collections::BTreeMap;
+use crate::multi_keychain::{keyring, Labels};
 
 /// Change set.
-#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(
+        deserialize = "K: Ord + Deserialize<'de>",
+        serialize = "K: Ord + Serialize",
+    ))
+)]
 pub struct ChangeSet<K: Ord> {
     /// Keyring changeset.
     pub keyring: keyring::ChangeSet<K>,
@@ -17,6 +27,19 @@ pub struct ChangeSet<K: Ord> {
     pub tx_graph: tx_graph::ChangeSet<ConfirmationBlockTime>,
     /// Changes to [`KeychainTxOutIndex`](keychain_txout::KeychainTxOutIndex).
     pub indexer: keychain_txout::ChangeSet,
+    /// Number of script pubkeys to look ahead of the last revealed index.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub lookahead: Option<u32>,
+    /// Whether derived script pubkeys are cached.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub use_spk_cache: Option<bool>,
+    /// Address and transaction labels.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub labels: Labels<K>,
+    /// Addresses [marked as used](crate::multi_keychain::Wallet::mark_used) by keychain and
+    /// derivation index, `false` where a mark was undone.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub used_marks: BTreeMap<K, BTreeMap<u32, bool>>,
 }
 
 impl<K: Ord> Default for ChangeSet<K> {
@@ -26,19 +49,51 @@ impl<K: Ord> Default for ChangeSet<K> {
             local_chain: Default::default(),
             tx_graph: Default::default(),
             indexer: Default::default(),
+            lookahead: None,
+            use_spk_cache: None,
+            labels: Labels::default(),
+            used_marks: BTreeMap::default(),
         }
     }
 }
 
 impl<K: Ord> Merge for ChangeSet<K> {
     fn merge(&mut self, other: Self) {
-        // merge keyring
+        // a removed keychain takes its earlier address labels and used marks with it
+        for keychain in &other.keyring.removed {
+            self.labels.addresses.remove(keychain);
+            self.used_marks.remove(keychain);
+        }
+
+        // merge keyring, which depends on the order: a keychain keeps the first descriptor
+        // assigned to it
         self.keyring.merge(other.keyring);
 
         // merge local chain, tx-graph, indexer
+        //
+        // The tx-graph and indexer merges are commutative: transactions and anchors are unioned,
+        // and last-seen times and revealed indices keep the maximum. So changesets that reveal
+        // indices and ones that add the transactions spending them can be merged in any order.
+        // The local chain isn't, a later checkpoint at the same height wins.
         Merge::merge(&mut self.local_chain, other.local_chain);
         Merge::merge(&mut self.tx_graph, other.tx_graph);
         Merge::merge(&mut self.indexer, other.indexer);
+
+        // settings, later values win
+        if other.lookahead.is_some() {
+            self.lookahead = other.lookahead;
+        }
+        if other.use_spk_cache.is_some() {
+            self.use_spk_cache = other.use_spk_cache;
+        }
+
+        // labels, later values win
+        self.labels.merge(other.labels);
+
+        // used marks, later values win
+        for (keychain, marks) in other.used_marks {
+            self.used_marks.entry(keychain).or_default().extend(marks);
+        }
     }
 
     fn is_empty(&self) -> bool {
@@ -46,6 +101,32 @@ impl<K: Ord> Merge for ChangeSet<K> {
             && self.local_chain.is_empty()
             && self.tx_graph.is_empty()
             && self.indexer.is_empty()
+            && self.lookahead.is_none()
+            && self.use_spk_cache.is_none()
+            && self.labels.is_empty()
+            && self.used_marks.is_empty()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K: Ord> core::fmt::Display for ChangeSet<K> {
+    /// Summarize the staged changes as counts rather than dumping them verbatim.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "ChangeSet {{ descriptors: {}, network: {}, blocks: {}, txs: {}, txouts: {}, \
+             revealed indices: {} }}",
+            self.keyring.descriptors.len(),
+            if self.keyring.network.is_some() {
+                "set"
+            } else {
+                "unset"
+            },
+            self.local_chain.blocks.len(),
+            self.tx_graph.txs.len(),
+            self.tx_graph.txouts.len(),
+            self.indexer.last_revealed.len(),
+        )
     }
 }
 
@@ -62,6 +143,12 @@ impl ChangeSet<DescriptorId> {
     pub const WALLET_TABLE_NAME: &'static str = "bdk_wallet";
     /// Name of table to store wallet descriptors.
     pub const DESCRIPTORS_TABLE_NAME: &'static str = "bdk_descriptor";
+    /// Name of table to store address labels.
+    pub const ADDRESS_LABELS_TABLE_NAME: &'static str = "bdk_address_label";
+    /// Name of table to store transaction labels.
+    pub const TX_LABELS_TABLE_NAME: &'static str = "bdk_tx_label";
+    /// Name of table to store addresses marked as used.
+    pub const USED_MARKS_TABLE_NAME: &'static str = "bdk_used_mark";
 
     /// Get v0 sqlite [ChangeSet] schema.
     pub fn schema_v0() -> alloc::string::String {
@@ -79,6 +166,63 @@ impl ChangeSet<DescriptorId> {
         )
     }
 
+    /// Get v1 sqlite [ChangeSet] schema. Adds the lookahead and spk cache settings.
+    pub fn schema_v1() -> alloc::string::String {
+        format!(
+            "ALTER TABLE {0} ADD COLUMN lookahead INTEGER; \
+            ALTER TABLE {0} ADD COLUMN use_spk_cache INTEGER;",
+            Self::WALLET_TABLE_NAME,
+        )
+    }
+
+    /// Get v2 sqlite [ChangeSet] schema. Adds the multipath descriptor each descriptor was split
+    /// from.
+    pub fn schema_v2() -> alloc::string::String {
+        format!(
+            "ALTER TABLE {} ADD COLUMN multipath_group TEXT;",
+            Self::DESCRIPTORS_TABLE_NAME,
+        )
+    }
+
+    /// Get v3 sqlite [ChangeSet] schema. Adds address and transaction labels.
+    pub fn schema_v3() -> alloc::string::String {
+        format!(
+            "CREATE TABLE {} ( \
+                descriptor_id TEXT NOT NULL, \
+                derivation_index INTEGER NOT NULL, \
+                label TEXT NOT NULL, \
+                PRIMARY KEY(descriptor_id, derivation_index) \
+            ); \
+            CREATE TABLE {} ( \
+                txid TEXT PRIMARY KEY NOT NULL, \
+                label TEXT NOT NULL \
+            );",
+            Self::ADDRESS_LABELS_TABLE_NAME,
+            Self::TX_LABELS_TABLE_NAME,
+        )
+    }
+
+    /// Get v4 sqlite [ChangeSet] schema. Adds the multipath path each descriptor was split from.
+    pub fn schema_v4() -> alloc::string::String {
+        format!(
+            "ALTER TABLE {} ADD COLUMN multipath_path INTEGER;",
+            Self::DESCRIPTORS_TABLE_NAME,
+        )
+    }
+
+    /// Get v5 sqlite [ChangeSet] schema. Adds addresses marked as used.
+    pub fn schema_v5() -> alloc::string::String {
+        format!(
+            "CREATE TABLE {} ( \
+                descriptor_id TEXT NOT NULL, \
+                derivation_index INTEGER NOT NULL, \
+                used INTEGER NOT NULL, \
+                PRIMARY KEY(descriptor_id, derivation_index) \
+            );",
+            Self::USED_MARKS_TABLE_NAME,
+        )
+    }
+
     /// Initializes tables and returns the aggregate data if the database is non-empty
     /// otherwise returns `Ok(None)`.
     pub fn initialize(db_tx: &rusqlite::Transaction) -> rusqlite::Result<Option<Self>> {
@@ -97,7 +241,14 @@ impl ChangeSet<DescriptorId> {
         bdk_chain::rusqlite_impl::migrate_schema(
             db_tx,
             Self::WALLET_SCHEMA_NAME,
-            &[&Self::schema_v0()],
+            &[
+                &Self::schema_v0(),
+                &Self::schema_v1(),
+                &Self::schema_v2(),
+                &Self::schema_v3(),
+                &Self::schema_v4(),
+                &Self::schema_v5(),
+            ],
         )?;
 
         local_chain::ChangeSet::init_sqlite_tables(db_tx)?;
@@ -117,32 +268,105 @@ impl ChangeSet<DescriptorId> {
 
         let mut keyring = keyring::ChangeSet::default();
 
-        // Read network
+        // Read network and settings
         let mut network_stmt = db_tx.prepare(&format!(
-            "SELECT network FROM {} WHERE id = 0",
+            "SELECT network, lookahead, use_spk_cache FROM {} WHERE id = 0",
             Self::WALLET_TABLE_NAME,
         ))?;
         let row = network_stmt
-            .query_row([], |row| row.get::<_, Impl<bitcoin::Network>>("network"))
+            .query_row([], |row| {
+                Ok((
+                    row.get::<_, Impl<bitcoin::Network>>("network")?,
+                    row.get::<_, Option<u32>>("lookahead")?,
+                    row.get::<_, Option<bool>>("use_spk_cache")?,
+                ))
+            })
             .optional()?;
-        if let Some(Impl(network)) = row {
+        if let Some((Impl(network), lookahead, use_spk_cache)) = row {
             keyring.network = Some(network);
+            changeset.lookahead = lookahead;
+            changeset.use_spk_cache = use_spk_cache;
         }
 
         // Read descriptors
         let mut descriptor_stmt = db_tx.prepare(&format!(
-            "SELECT descriptor_id, descriptor FROM {}",
+            "SELECT descriptor_id, descriptor, multipath_group, multipath_path FROM {}",
             Self::DESCRIPTORS_TABLE_NAME
         ))?;
         let rows = descriptor_stmt.query_map([], |row| {
             Ok((
                 row.get::<_, Impl<DescriptorId>>("descriptor_id")?,
                 row.get::<_, Impl<Descriptor<DescriptorPublicKey>>>("descriptor")?,
+                row.get::<_, Option<Impl<DescriptorId>>>("multipath_group")?,
+                row.get::<_, Option<u32>>("multipath_path")?,
             ))
         })?;
         for row in rows {
-            let (Impl(did), Impl(descriptor)) = row?;
+            let (Impl(did), Impl(descriptor), group, path) = row?;
             keyring.descriptors.insert(did, descriptor);
+            if let Some(Impl(group)) = group {
+                keyring.multipath_groups.insert(did, group);
+            }
+            if let Some(path) = path {
+                keyring.multipath_paths.insert(did, path);
+            }
+        }
+
+        // Read labels
+        let mut address_label_stmt = db_tx.prepare(&format!(
+            "SELECT descriptor_id, derivation_index, label FROM {}",
+            Self::ADDRESS_LABELS_TABLE_NAME,
+        ))?;
+        let rows = address_label_stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, Impl<DescriptorId>>("descriptor_id")?,
+                row.get::<_, u32>("derivation_index")?,
+                row.get::<_, alloc::string::String>("label")?,
+            ))
+        })?;
+        for row in rows {
+            let (Impl(did), index, label) = row?;
+            changeset
+                .labels
+                .addresses
+                .entry(did)
+                .or_default()
+                .insert(index, label);
+        }
+        let mut tx_label_stmt = db_tx.prepare(&format!(
+            "SELECT txid, label FROM {}",
+            Self::TX_LABELS_TABLE_NAME,
+        ))?;
+        let rows = tx_label_stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, Impl<bitcoin::Txid>>("txid")?,
+                row.get::<_, alloc::string::String>("label")?,
+            ))
+        })?;
+        for row in rows {
+            let (Impl(txid), label) = row?;
+            changeset.labels.txs.insert(txid, label);
+        }
+
+        // Read used marks
+        let mut used_mark_stmt = db_tx.prepare(&format!(
+            "SELECT descriptor_id, derivation_index, used FROM {}",
+            Self::USED_MARKS_TABLE_NAME,
+        ))?;
+        let rows = used_mark_stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, Impl<DescriptorId>>("descriptor_id")?,
+                row.get::<_, u32>("derivation_index")?,
+                row.get::<_, bool>("used")?,
+            ))
+        })?;
+        for row in rows {
+            let (Impl(did), index, used) = row?;
+            changeset
+                .used_marks
+                .entry(did)
+                .or_default()
+                .insert(index, used);
         }
 
         changeset.keyring = keyring;
@@ -162,7 +386,8 @@ impl ChangeSet<DescriptorId> {
 
         // Write network
         let mut network_stmt = db_tx.prepare_cached(&format!(
-            "REPLACE INTO {}(id, network) VALUES(:id, :network)",
+            "INSERT INTO {}(id, network) VALUES(:id, :network) \
+             ON CONFLICT(id) DO UPDATE SET network = :network",
             Self::WALLET_TABLE_NAME,
         ))?;
         if let Some(network) = keyring.network {
@@ -172,6 +397,41 @@ impl ChangeSet<DescriptorId> {
             })?;
         }
 
+        // Write settings
+        if let Some(lookahead) = self.lookahead {
+            db_tx
+                .prepare_cached(&format!(
+                    "UPDATE {} SET lookahead = :lookahead WHERE id = 0",
+                    Self::WALLET_TABLE_NAME,
+                ))?
+                .execute(named_params! { ":lookahead": lookahead })?;
+        }
+        if let Some(use_spk_cache) = self.use_spk_cache {
+            db_tx
+                .prepare_cached(&format!(
+                    "UPDATE {} SET use_spk_cache = :use_spk_cache WHERE id = 0",
+                    Self::WALLET_TABLE_NAME,
+                ))?
+                .execute(named_params! { ":use_spk_cache": use_spk_cache })?;
+        }
+
+        // Delete removed descriptors, along with their address labels and used marks
+        for table in [
+            Self::DESCRIPTORS_TABLE_NAME,
+            Self::ADDRESS_LABELS_TABLE_NAME,
+            Self::USED_MARKS_TABLE_NAME,
+        ] {
+            let mut remove_stmt = db_tx.prepare_cached(&format!(
+                "DELETE FROM {} WHERE descriptor_id = :descriptor_id",
+                table,
+            ))?;
+            for &did in &keyring.removed {
+                remove_stmt.execute(named_params! {
+                    ":descriptor_id": Impl(did),
+                })?;
+            }
+        }
+
         // Write descriptors
         let mut descriptor_stmt = db_tx.prepare_cached(&format!(
             "INSERT OR IGNORE INTO {}(descriptor_id, descriptor) VALUES(:descriptor_id, :descriptor)",
@@ -183,6 +443,68 @@ impl ChangeSet<DescriptorId> {
                 ":descriptor": Impl(descriptor.clone()),
             })?;
         }
+        let mut group_stmt = db_tx.prepare_cached(&format!(
+            "UPDATE {} SET multipath_group = :multipath_group WHERE descriptor_id = :descriptor_id",
+            Self::DESCRIPTORS_TABLE_NAME,
+        ))?;
+        for (&did, &group) in &keyring.multipath_groups {
+            group_stmt.execute(named_params! {
+                ":descriptor_id": Impl(did),
+                ":multipath_group": Impl(group),
+            })?;
+        }
+        let mut path_stmt = db_tx.prepare_cached(&format!(
+            "UPDATE {} SET multipath_path = :multipath_path WHERE descriptor_id = :descriptor_id",
+            Self::DESCRIPTORS_TABLE_NAME,
+        ))?;
+        for (&did, &path) in &keyring.multipath_paths {
+            path_stmt.execute(named_params! {
+                ":descriptor_id": Impl(did),
+                ":multipath_path": path,
+            })?;
+        }
+
+        // Write labels
+        let mut address_label_stmt = db_tx.prepare_cached(&format!(
+            "INSERT OR REPLACE INTO {}(descriptor_id, derivation_index, label) \
+             VALUES(:descriptor_id, :derivation_index, :label)",
+            Self::ADDRESS_LABELS_TABLE_NAME,
+        ))?;
+        for (&did, labels) in &self.labels.addresses {
+            for (&index, label) in labels {
+                address_label_stmt.execute(named_params! {
+                    ":descriptor_id": Impl(did),
+                    ":derivation_index": index,
+                    ":label": label,
+                })?;
+            }
+        }
+        let mut tx_label_stmt = db_tx.prepare_cached(&format!(
+            "INSERT OR REPLACE INTO {}(txid, label) VALUES(:txid, :label)",
+            Self::TX_LABELS_TABLE_NAME,
+        ))?;
+        for (&txid, label) in &self.labels.txs {
+            tx_label_stmt.execute(named_params! {
+                ":txid": Impl(txid),
+                ":label": label,
+            })?;
+        }
+
+        // Write used marks
+        let mut used_mark_stmt = db_tx.prepare_cached(&format!(
+            "INSERT OR REPLACE INTO {}(descriptor_id, derivation_index, used) \
+             VALUES(:descriptor_id, :derivation_index, :used)",
+            Self::USED_MARKS_TABLE_NAME,
+        ))?;
+        for (&did, marks) in &self.used_marks {
+            for (&index, &used) in marks {
+                used_mark_stmt.execute(named_params! {
+                    ":descriptor_id": Impl(did),
+                    ":derivation_index": index,
+                    ":used": used,
+                })?;
+            }
+        }
 
         self.local_chain.persist_to_sqlite(db_tx)?;
         self.tx_graph.persist_to_sqlite(db_tx)?;
@@ -226,3 +548,63 @@ impl<K: Ord> From<keychain_txout::ChangeSet> for ChangeSet<K> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::multi_keychain::test_utils::*;
+    use crate::multi_keychain::{KeyRing, Wallet};
+    use alloc::sync::Arc;
+    use bitcoin::Amount;
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn changeset_json_round_trip() {
+        let mut keyring = KeyRing::new(bitcoin::Network::Signet);
+        let (receive, _) = keyring.add_multipath_descriptor(WPKH_MULTIPATH);
+        let mut wallet = Wallet::new(keyring);
+        wallet.reveal_next_address(receive).unwrap();
+        wallet.set_address_label(receive, 0, "donations".into());
+        wallet.mark_used(receive, 0);
+        let changeset = wallet.take_staged().unwrap();
+
+        let json = serde_json::to_string(&changeset).unwrap();
+        let decoded: ChangeSet<bdk_chain::DescriptorId> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, changeset);
+    }
+
+    #[test]
+    fn merge_reveal_and_spend_in_any_order() {
+        let mut wallet = new_wallet();
+        let outpoint =
+            receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(100_000), 1);
+        let base = wallet.take_staged().unwrap();
+
+        let ((_, index), address) = wallet.reveal_next_address(Keychain::External).unwrap();
+        let reveal = wallet.take_staged().unwrap();
+        let tx = spending_tx(&[outpoint], address.script_pubkey(), Amount::from_sat(99_000));
+        wallet.apply_unconfirmed_txs([(Arc::new(tx), 10)]);
+        let spend = wallet.take_staged().unwrap();
+
+        let mut in_order = base.clone();
+        in_order.merge(reveal.clone());
+        in_order.merge(spend.clone());
+        let mut reversed = base;
+        reversed.merge(spend);
+        reversed.merge(reveal);
+        assert_eq!(in_order, reversed);
+
+        let in_order = Wallet::from_changeset(in_order).unwrap();
+        let reversed = Wallet::from_changeset(reversed).unwrap();
+        assert_eq!(in_order.balance(), reversed.balance());
+        assert_eq!(in_order.balance(), wallet.balance());
+        assert_eq!(
+            in_order.index().last_revealed_index(Keychain::External),
+            Some(index)
+        );
+        assert_eq!(
+            reversed.index().last_revealed_index(Keychain::External),
+            Some(index)
+        );
+    }
+}
@@ -1,5 +1,9 @@
+use alloc::string::{String, ToString};
+
 use bdk_wallet::descriptor::DescriptorError;
-use bitcoin::Network;
+use bitcoin::{Network, NetworkKind, OutPoint};
+
+use crate::bdk_chain::local_chain::{AlterCheckPointError, CannotConnectError, MissingGenesisError};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum KeyRingError {
@@ -10,15 +14,31 @@ pub enum KeyRingError {
     /// The provided descriptor is invalid - single when multipath expected  
     SingleDescriptorNotAllowed,
     /// Network mismatch between descriptor and keyring
-    NetworkMismatch { expected: Network, found: Network },
+    ///
+    /// Keys only encode whether they are for mainnet or a test network, so only the kind of
+    /// network `found` is known.
+    NetworkMismatch { expected: Network, found: NetworkKind },
     /// Keyring is empty when an operation requires descriptors
     EmptyKeyRing,
     /// Keychain not found in the keyring
     KeychainNotFound,
-    /// Descriptor parsing failed
-    DescriptorParsing,
+    /// Descriptor parsing failed, with the reason reported by the parser
+    DescriptorParsing(String),
     /// Address generation failed
     AddressGeneration,
+    /// The descriptor checksum is missing or does not match the descriptor
+    ChecksumMismatch,
+    /// The descriptor does not follow the standard `.../0/*` receive derivation
+    NonStandardDerivation,
+    /// Keychain still has unspent outputs
+    KeychainHasFunds,
+    /// The multipath descriptor does not have exactly two paths
+    MultipathPathCount(usize),
+    /// The descriptor has hardened derivation steps after an extended public key, so it can't
+    /// be derived
+    UnusableDescriptor,
+    /// The descriptor with private keys is not the keychain's descriptor
+    KeymapMismatch,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -53,6 +73,15 @@ pub enum TxBuilderError {
     InvalidRecipient,
     /// PSBT creation failed
     PsbtCreation,
+    /// No fee rate estimate available for the confirmation target
+    FeeEstimation,
+    /// The only spendable outputs are coinbase outputs that have not matured yet
+    ImmatureCoinbase,
+    /// The output added with `TxBuilder::add_utxo` isn't a spendable output of the wallet
+    UnknownUtxo(OutPoint),
+    /// The outputs added with `TxBuilder::add_utxo` belong to more than one keychain, though
+    /// the transaction is restricted to a single keychain
+    MixedKeychains,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -67,6 +96,21 @@ pub enum SigningError {
     SigningFailed,
     /// Input not found
     InputNotFound,
+    /// Keychain of an input not found
+    KeychainNotFound,
+    /// The output spent by the input at this index is unknown
+    MissingUtxo(usize),
+    /// The outputs spend more than the inputs provide
+    NegativeFee,
+}
+
+/// Error computing the fee of a PSBT.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PsbtFeeError {
+    /// The output spent by the input at this index is unknown
+    MissingUtxo(usize),
+    /// The outputs spend more than the inputs provide
+    NegativeFee,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -81,6 +125,28 @@ pub enum AddressGenerationError {
     NetworkIncompatible,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainError {
+    /// Update does not connect with the local chain
+    CannotConnect { try_include_height: u32 },
+    /// Local chain is missing the genesis block
+    MissingGenesis,
+    /// Reorg would replace a block that can't be replaced, such as genesis
+    Reorg { height: u32 },
+    /// Resetting the chain from height 0 would leave nothing but the genesis block
+    CannotResetGenesis,
+}
+
+/// Error importing BIP-329 labels into a wallet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LabelImportError {
+    /// A line isn't a valid BIP-329 record
+    InvalidRecord {
+        /// Number of the line, counting from 1
+        line: usize,
+    },
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum WalletError {
     /// KeyRing related error
@@ -93,6 +159,8 @@ pub enum WalletError {
     Signing(SigningError),
     /// Address generation error
     AddressGeneration(AddressGenerationError),
+    /// Local chain error
+    Chain(ChainError),
 }
 
 // Only implement Display and Error traits when std is available
@@ -118,15 +186,41 @@ mod display_impls {
                 ),
                 KeyRingError::NetworkMismatch { expected, found } => write!(
                     f,
-                    "Network mismatch: expected {}, found {}",
-                    expected, found
+                    "Network mismatch: expected {}, found a {} key",
+                    expected,
+                    match found {
+                        NetworkKind::Main => "mainnet",
+                        NetworkKind::Test => "test network",
+                    }
                 ),
                 KeyRingError::EmptyKeyRing => write!(f, "KeyRing is empty"),
                 KeyRingError::KeychainNotFound => write!(f, "Keychain not found in keyring"),
-                KeyRingError::DescriptorParsing => write!(f, "Failed to parse descriptor"),
+                KeyRingError::DescriptorParsing(reason) => {
+                    write!(f, "Failed to parse descriptor: {}", reason)
+                }
                 KeyRingError::AddressGeneration => {
                     write!(f, "Failed to generate address from descriptor")
                 }
+                KeyRingError::ChecksumMismatch => {
+                    write!(f, "Descriptor checksum is missing or does not match")
+                }
+                KeyRingError::NonStandardDerivation => write!(
+                    f,
+                    "Descriptor does not follow the standard .../0/* receive derivation"
+                ),
+                KeyRingError::KeychainHasFunds => write!(f, "Keychain still has unspent outputs"),
+                KeyRingError::MultipathPathCount(count) => write!(
+                    f,
+                    "Multipath descriptor has {} paths, expected exactly two",
+                    count
+                ),
+                KeyRingError::UnusableDescriptor => write!(
+                    f,
+                    "Descriptor has hardened derivation after an xpub, which needs the private key"
+                ),
+                KeyRingError::KeymapMismatch => {
+                    write!(f, "Private keys don't belong to the keychain's descriptor")
+                }
             }
         }
     }
@@ -163,6 +257,18 @@ mod display_impls {
                 TxBuilderError::DustOutput => write!(f, "Output below dust threshold"),
                 TxBuilderError::InvalidRecipient => write!(f, "Invalid recipient address"),
                 TxBuilderError::PsbtCreation => write!(f, "PSBT creation failed"),
+                TxBuilderError::FeeEstimation => {
+                    write!(f, "No fee rate estimate available for the confirmation target")
+                }
+                TxBuilderError::ImmatureCoinbase => {
+                    write!(f, "Coinbase outputs have not matured yet")
+                }
+                TxBuilderError::UnknownUtxo(outpoint) => {
+                    write!(f, "{} is not a spendable output of the wallet", outpoint)
+                }
+                TxBuilderError::MixedKeychains => {
+                    write!(f, "Manually added outputs belong to more than one keychain")
+                }
             }
         }
     }
@@ -175,6 +281,22 @@ mod display_impls {
                 SigningError::AlreadyFinalized => write!(f, "PSBT is already finalized"),
                 SigningError::SigningFailed => write!(f, "Signing failed"),
                 SigningError::InputNotFound => write!(f, "Input not found"),
+                SigningError::KeychainNotFound => write!(f, "Keychain of an input not found"),
+                SigningError::MissingUtxo(index) => {
+                    write!(f, "Output spent by input {} is unknown", index)
+                }
+                SigningError::NegativeFee => write!(f, "Outputs exceed the value of the inputs"),
+            }
+        }
+    }
+
+    impl fmt::Display for PsbtFeeError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                PsbtFeeError::MissingUtxo(index) => {
+                    write!(f, "Output spent by input {} is unknown", index)
+                }
+                PsbtFeeError::NegativeFee => write!(f, "Outputs exceed the value of the inputs"),
             }
         }
     }
@@ -190,6 +312,35 @@ mod display_impls {
         }
     }
 
+    impl fmt::Display for ChainError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                ChainError::CannotConnect { try_include_height } => write!(
+                    f,
+                    "Cannot connect to local chain, try include height {}",
+                    try_include_height
+                ),
+                ChainError::MissingGenesis => write!(f, "Local chain is missing the genesis block"),
+                ChainError::Reorg { height } => {
+                    write!(f, "Cannot replace block at height {} during reorg", height)
+                }
+                ChainError::CannotResetGenesis => {
+                    write!(f, "Cannot reset the chain from the genesis block")
+                }
+            }
+        }
+    }
+
+    impl fmt::Display for LabelImportError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                LabelImportError::InvalidRecord { line } => {
+                    write!(f, "Line {} is not a valid BIP-329 record", line)
+                }
+            }
+        }
+    }
+
     impl fmt::Display for WalletError {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
             match self {
@@ -198,6 +349,7 @@ mod display_impls {
                 WalletError::TxBuilder(e) => write!(f, "Transaction builder error: {}", e),
                 WalletError::Signing(e) => write!(f, "Signing error: {}", e),
                 WalletError::AddressGeneration(e) => write!(f, "Address generation error: {}", e),
+                WalletError::Chain(e) => write!(f, "Chain error: {}", e),
             }
         }
     }
@@ -206,7 +358,10 @@ mod display_impls {
     impl Error for PersistenceError {}
     impl Error for TxBuilderError {}
     impl Error for SigningError {}
+    impl Error for PsbtFeeError {}
     impl Error for AddressGenerationError {}
+    impl Error for ChainError {}
+    impl Error for LabelImportError {}
     impl Error for WalletError {}
 }
 
@@ -241,16 +396,45 @@ impl From<AddressGenerationError> for WalletError {
     }
 }
 
+impl From<ChainError> for WalletError {
+    fn from(err: ChainError) -> Self {
+        WalletError::Chain(err)
+    }
+}
+
 // External error conversions
 impl From<DescriptorError> for KeyRingError {
-    fn from(_: DescriptorError) -> Self {
-        KeyRingError::DescriptorParsing
+    fn from(err: DescriptorError) -> Self {
+        match err {
+            DescriptorError::InvalidDescriptorChecksum => KeyRingError::ChecksumMismatch,
+            err => KeyRingError::DescriptorParsing(err.to_string()),
+        }
+    }
+}
+
+impl From<CannotConnectError> for ChainError {
+    fn from(err: CannotConnectError) -> Self {
+        ChainError::CannotConnect {
+            try_include_height: err.try_include_height,
+        }
+    }
+}
+
+impl From<MissingGenesisError> for ChainError {
+    fn from(_: MissingGenesisError) -> Self {
+        ChainError::MissingGenesis
+    }
+}
+
+impl From<AlterCheckPointError> for ChainError {
+    fn from(err: AlterCheckPointError) -> Self {
+        ChainError::Reorg { height: err.height }
     }
 }
 
 #[cfg(feature = "rusqlite")]
-impl From<bdk_chain::rusqlite::Error> for PersistenceError {
-    fn from(_: bdk_chain::rusqlite::Error) -> Self {
+impl From<crate::bdk_chain::rusqlite::Error> for PersistenceError {
+    fn from(_: crate::bdk_chain::rusqlite::Error) -> Self {
         PersistenceError::Database
     }
 }
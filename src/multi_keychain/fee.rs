@@ -0,0 +1,24 @@
+//! Fee rate estimation.
+
+use bitcoin::FeeRate;
+
+/// Resolves a confirmation target to a concrete [`FeeRate`].
+///
+/// Implement this on top of whatever fee source is available (e.g. an Esplora fee histogram) and
+/// hand it to the transaction builder to avoid hardcoding fee rates.
+pub trait FeeEstimator {
+    /// Estimate the fee rate needed to confirm within `target_blocks` blocks.
+    ///
+    /// Returns `None` if no estimate is available for the given target.
+    fn estimate_fee_rate(&self, target_blocks: u16) -> Option<FeeRate>;
+}
+
+/// [`FeeEstimator`] that returns the same fee rate for every confirmation target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaticFeeEstimator(pub FeeRate);
+
+impl FeeEstimator for StaticFeeEstimator {
+    fn estimate_fee_rate(&self, _target_blocks: u16) -> Option<FeeRate> {
+        Some(self.0)
+    }
+}
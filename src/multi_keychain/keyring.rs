@@ -1,22 +1,52 @@
+use alloc::string::{String, ToString};
+use core::fmt;
+use alloc::vec::Vec;
 use bdk_chain::{DescriptorExt, Merge};
-use bdk_wallet::descriptor::IntoWalletDescriptor;
+use bdk_wallet::descriptor::{DescriptorError, IntoWalletDescriptor};
+use bdk_wallet::keys::KeyError;
 use bitcoin::{
+    bip32::{ChildNumber, DerivationPath},
+    hashes::{sha256, Hash},
     secp256k1::{All, Secp256k1},
-    Network,
+    Network, NetworkKind,
 };
-use miniscript::{Descriptor, DescriptorPublicKey};
+use miniscript::descriptor::{checksum::desc_checksum, KeyMap, Wildcard};
+use miniscript::{
+    translate_hash_clone, Descriptor, DescriptorPublicKey, ForEachKey, TranslateErr, TranslatePk,
+    Translator,
+};
+#[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
 use crate::bdk_chain;
-use crate::collections::BTreeMap;
+use crate::collections::{BTreeMap, BTreeSet};
+use crate::multi_keychain::labels::write_json_string;
 use crate::multi_keychain::{Did, errors::KeyRingError};
 
 /// KeyRing.
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct KeyRing<K> {
     pub(crate) secp: Secp256k1<All>,
     pub(crate) network: Network,
     pub(crate) descriptors: BTreeMap<K, Descriptor<DescriptorPublicKey>>,
+    pub(crate) multipath_groups: BTreeMap<K, Did>,
+    /// Index of the multipath path each keychain was split from, `0` for receive and `1` for
+    /// change.
+    pub(crate) multipath_paths: BTreeMap<K, u32>,
+    /// Secret keys of descriptors added with private keys. These are never persisted.
+    pub(crate) keymaps: BTreeMap<K, KeyMap>,
+}
+
+impl<K: fmt::Debug> fmt::Debug for KeyRing<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Leave out `keymaps` so secret keys don't end up in logs.
+        f.debug_struct("KeyRing")
+            .field("network", &self.network)
+            .field("descriptors", &self.descriptors)
+            .field("multipath_groups", &self.multipath_groups)
+            .field("multipath_paths", &self.multipath_paths)
+            .finish_non_exhaustive()
+    }
 }
 
 impl<K> KeyRing<K>
@@ -29,22 +59,37 @@ where
             secp: Secp256k1::new(),
             network,
             descriptors: BTreeMap::default(),
+            multipath_groups: BTreeMap::default(),
+            multipath_paths: BTreeMap::default(),
+            keymaps: BTreeMap::default(),
+        }
+    }
+
+    /// Start building a [`KeyRing`] for `network`, see [`KeyRingBuilder`].
+    pub fn builder(network: Network) -> KeyRingBuilder<K> {
+        KeyRingBuilder {
+            keyring: Self::new(network),
+            error: None,
         }
     }
 
     /// Add descriptor with validation
+    ///
+    /// A descriptor without a wildcard, e.g. `wpkh(<key>)`, is not ranged: it has a single
+    /// address, at index 0, which revealing the next address always returns.
     pub fn add_descriptor_validated(
         &mut self,
         keychain: K,
         descriptor: impl IntoWalletDescriptor
     ) -> Result<(), KeyRingError> {
-        let (descriptor, _) = descriptor
+        let (descriptor, keymap) = descriptor
             .into_wallet_descriptor(&self.secp, self.network)
-            .map_err(|_| KeyRingError::DescriptorParsing)?;
+            .map_err(|e| descriptor_error(self.network, e))?;
 
         if descriptor.is_multipath() {
             return Err(KeyRingError::MultipathDescriptorNotAllowed);
         }
+        check_derivable(&descriptor)?;
 
         if self.descriptors.contains_key(&keychain) {
             return Err(KeyRingError::DuplicateDescriptor);
@@ -54,24 +99,117 @@ where
         descriptor.at_derivation_index(0)
             .map_err(|_| KeyRingError::AddressGeneration)?;
 
+        self.insert_keymap(keychain.clone(), keymap);
         self.descriptors.insert(keychain, descriptor);
         Ok(())
     }
 
+    /// Add descriptor with validation, after verifying its `#checksum` suffix.
+    ///
+    /// Returns [`KeyRingError::ChecksumMismatch`] if the checksum is absent or doesn't match the
+    /// one computed from the descriptor.
+    pub fn add_descriptor_checked(
+        &mut self,
+        keychain: K,
+        descriptor_with_checksum: &str,
+    ) -> Result<(), KeyRingError> {
+        let (descriptor, checksum) = descriptor_with_checksum
+            .split_once('#')
+            .ok_or(KeyRingError::ChecksumMismatch)?;
+        let expected = desc_checksum(descriptor).map_err(|e| KeyRingError::DescriptorParsing(e.to_string()))?;
+        if checksum != expected {
+            return Err(KeyRingError::ChecksumMismatch);
+        }
+
+        self.add_descriptor_validated(keychain, descriptor)
+    }
+
     /// Add descriptor, must not be [multipath](miniscript::Descriptor::is_multipath).
     pub fn add_descriptor(&mut self, keychain: K, descriptor: impl IntoWalletDescriptor) {
-        let descriptor = descriptor
+        let (descriptor, keymap) = descriptor
             .into_wallet_descriptor(&self.secp, self.network)
-            .expect("err: invalid descriptor")
-            .0;
+            .expect("err: invalid descriptor");
         assert!(
             !descriptor.is_multipath(),
             "err: Use `add_multipath_descriptor` instead"
         );
+        check_derivable(&descriptor).expect("err: hardened derivation after an xpub");
 
+        self.insert_keymap(keychain.clone(), keymap);
         self.descriptors.insert(keychain, descriptor);
     }
 
+    /// Add a `<0;1>` multipath descriptor, assigning the `0` path to the `external` keychain and
+    /// the `1` path to the `internal` keychain.
+    ///
+    /// Fails with [`KeyRingError::MultipathPathCount`] if the descriptor doesn't have exactly two
+    /// paths. Nothing is added unless both descriptors are valid.
+    pub fn add_multipath_descriptor_split(
+        &mut self,
+        external: K,
+        internal: K,
+        descriptor: impl IntoWalletDescriptor,
+    ) -> Result<(), KeyRingError> {
+        let (descriptor, keymap) = descriptor
+            .into_wallet_descriptor(&self.secp, self.network)
+            .map_err(|e| descriptor_error(self.network, e))?;
+
+        if !descriptor.is_multipath() {
+            return Err(KeyRingError::SingleDescriptorNotAllowed);
+        }
+        check_derivable(&descriptor)?;
+
+        let group = multipath_group_id(&descriptor);
+        let descriptors = descriptor
+            .into_single_descriptors()
+            .map_err(|e| KeyRingError::DescriptorParsing(e.to_string()))?;
+        let [external_descriptor, internal_descriptor]: [_; 2] = descriptors
+            .try_into()
+            .map_err(|descriptors: Vec<_>| KeyRingError::MultipathPathCount(descriptors.len()))?;
+
+        if external == internal
+            || self.descriptors.contains_key(&external)
+            || self.descriptors.contains_key(&internal)
+        {
+            return Err(KeyRingError::DuplicateDescriptor);
+        }
+
+        for descriptor in [&external_descriptor, &internal_descriptor] {
+            descriptor
+                .at_derivation_index(0)
+                .map_err(|_| KeyRingError::AddressGeneration)?;
+        }
+
+        self.multipath_groups.insert(external.clone(), group);
+        self.multipath_groups.insert(internal.clone(), group);
+        self.multipath_paths.insert(external.clone(), 0);
+        self.multipath_paths.insert(internal.clone(), 1);
+        self.insert_keymap(external.clone(), keymap.clone());
+        self.insert_keymap(internal.clone(), keymap);
+        self.descriptors.insert(external, external_descriptor);
+        self.descriptors.insert(internal, internal_descriptor);
+        Ok(())
+    }
+
+    /// Derive the change keychain from the `receive` keychain and register it as `change`.
+    ///
+    /// The receive descriptor must follow the standard `.../0/*` derivation, in which case the
+    /// change descriptor is its `.../1/*` sibling.
+    pub fn derive_change_keychain(&mut self, receive: K, change: K) -> Result<(), KeyRingError> {
+        let receive_descriptor = self
+            .descriptors
+            .get(&receive)
+            .ok_or(KeyRingError::KeychainNotFound)?;
+        let change_descriptor = receive_descriptor
+            .translate_pk(&mut ChangePathTranslator)
+            .map_err(|e| match e {
+                TranslateErr::TranslatorErr(e) => e,
+                TranslateErr::OuterError(e) => KeyRingError::DescriptorParsing(e.to_string()),
+            })?;
+
+        self.add_descriptor_validated(change, change_descriptor)
+    }
+
     /// Validate the entire keyring
     pub fn validate(&self) -> Result<(), KeyRingError> {
         if self.descriptors.is_empty() {
@@ -92,6 +230,13 @@ where
         self.descriptors.contains_key(keychain)
     }
 
+    /// Whether the descriptor of `keychain` has a wildcard, i.e. derives an address per index.
+    ///
+    /// Returns `None` if the keychain is unknown.
+    pub fn is_ranged(&self, keychain: &K) -> Option<bool> {
+        self.descriptors.get(keychain).map(|descriptor| descriptor.has_wildcard())
+    }
+
     /// Get descriptor count
     pub fn descriptor_count(&self) -> usize {
         self.descriptors.len()
@@ -104,9 +249,102 @@ where
 
     /// Remove a keychain and return whether it existed
     pub fn remove_keychain(&mut self, keychain: &K) -> bool {
+        self.multipath_groups.remove(keychain);
+        self.multipath_paths.remove(keychain);
+        self.keymaps.remove(keychain);
         self.descriptors.remove(keychain).is_some()
     }
 
+    /// Remove all keychains
+    pub fn clear(&mut self) {
+        self.descriptors.clear();
+        self.multipath_groups.clear();
+        self.multipath_paths.clear();
+        self.keymaps.clear();
+    }
+
+    /// Whether no keychain has secret keys, i.e. the keyring can't sign on its own.
+    pub fn is_watch_only(&self) -> bool {
+        self.keymaps.is_empty()
+    }
+
+    /// Whether `keychain` was added from a descriptor with secret keys.
+    pub fn has_secret(&self, keychain: &K) -> bool {
+        self.keymaps.contains_key(keychain)
+    }
+
+    /// Add the secret keys of `keychain` from `descriptor`, its descriptor with private keys.
+    ///
+    /// Secret keys are never persisted, so this is how a keyring loaded from a changeset gets
+    /// them back to sign. Fails with [`KeyRingError::KeychainNotFound`] if the keychain is
+    /// unknown, and [`KeyRingError::KeymapMismatch`] if `descriptor` isn't its descriptor.
+    pub fn add_keymap(
+        &mut self,
+        keychain: K,
+        descriptor: impl IntoWalletDescriptor,
+    ) -> Result<(), KeyRingError> {
+        let (descriptor, keymap) = descriptor
+            .into_wallet_descriptor(&self.secp, self.network)
+            .map_err(|e| descriptor_error(self.network, e))?;
+        let existing = self
+            .descriptors
+            .get(&keychain)
+            .ok_or(KeyRingError::KeychainNotFound)?;
+        if *existing != descriptor {
+            return Err(KeyRingError::KeymapMismatch);
+        }
+        self.insert_keymap(keychain, keymap);
+        Ok(())
+    }
+
+    /// Keep the secret keys of `keychain`, if there are any.
+    fn insert_keymap(&mut self, keychain: K, keymap: KeyMap) {
+        if !keymap.is_empty() {
+            self.keymaps.insert(keychain, keymap);
+        }
+    }
+
+    /// Get the id of the multipath descriptor `keychain` was split from, if any.
+    pub fn multipath_group(&self, keychain: &K) -> Option<Did> {
+        self.multipath_groups.get(keychain).copied()
+    }
+
+    /// Group the keychains split from the same multipath descriptor, e.g. the receive and change
+    /// keychains of an account, by the id of that multipath descriptor.
+    pub fn multipath_groups(&self) -> BTreeMap<Did, Vec<K>> {
+        let mut groups: BTreeMap<Did, Vec<K>> = BTreeMap::new();
+        for (keychain, group) in &self.multipath_groups {
+            groups.entry(*group).or_default().push(keychain.clone());
+        }
+        groups
+    }
+
+    /// Get the index of the multipath path `keychain` was split from, if any.
+    pub fn multipath_path(&self, keychain: &K) -> Option<u32> {
+        self.multipath_paths.get(keychain).copied()
+    }
+
+    /// Get the receive (path `0`) and change (path `1`) keychains split from the multipath
+    /// descriptor `group`, if both exist.
+    pub fn receive_change_pair(&self, group: Did) -> Option<(K, K)> {
+        let path_keychain = |path: u32| {
+            self.multipath_groups
+                .iter()
+                .find(|(keychain, g)| {
+                    **g == group && self.multipath_paths.get(*keychain) == Some(&path)
+                })
+                .map(|(keychain, _)| keychain.clone())
+        };
+        Some((path_keychain(0)?, path_keychain(1)?))
+    }
+
+    /// Get the change keychain paired with `keychain`, i.e. the path `1` keychain split from the
+    /// same multipath descriptor, if any.
+    pub fn change_keychain_of(&self, keychain: &K) -> Option<K> {
+        let group = self.multipath_group(keychain)?;
+        self.receive_change_pair(group).map(|(_, change)| change)
+    }
+
     /// Check if keyring is empty
     pub fn is_empty(&self) -> bool {
         self.descriptors.is_empty()
@@ -117,86 +355,408 @@ where
         self.descriptors.keys()
     }
 
+    /// Iterate over `(keychain, descriptor)` pairs without cloning.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&K, &Descriptor<DescriptorPublicKey>)> {
+        self.descriptors.iter()
+    }
+
+    /// Export the descriptors as request objects for Bitcoin Core's `importdescriptors` RPC, one
+    /// JSON object per descriptor.
+    ///
+    /// Each descriptor is in `desc#checksum` form. Ranged descriptors get a `range` covering
+    /// Core's default keypool of 1000 addresses, and are marked `active` so Core derives new
+    /// addresses from them. The change path of a multipath descriptor is marked `internal`, so
+    /// Core uses it for change. The `timestamp` is `0` so Core rescans the whole chain, as the
+    /// keyring doesn't know when the descriptors were first used.
+    pub fn export_core_descriptors(&self) -> Vec<String> {
+        self.descriptors
+            .iter()
+            .map(|(keychain, descriptor)| {
+                let mut request = String::from("{\"desc\":");
+                write_json_string(&mut request, &descriptor.to_string());
+                request.push_str(",\"timestamp\":0");
+                if descriptor.has_wildcard() {
+                    request.push_str(",\"active\":true,\"range\":[0,999]");
+                }
+                let internal = self.multipath_paths.get(keychain) == Some(&1);
+                request.push_str(if internal {
+                    ",\"internal\":true}"
+                } else {
+                    ",\"internal\":false}"
+                });
+                request
+            })
+            .collect()
+    }
+
+    /// Compare the descriptors of this keyring with those of `other`, e.g. to reconcile keyrings
+    /// that were modified independently on different devices.
+    pub fn diff(&self, other: &KeyRing<K>) -> KeyRingDiff<K> {
+        let mut diff = KeyRingDiff::default();
+        for (keychain, descriptor) in &self.descriptors {
+            match other.descriptors.get(keychain) {
+                None => {
+                    diff.only_in_self.insert(keychain.clone(), descriptor.clone());
+                }
+                Some(other_descriptor) if other_descriptor != descriptor => {
+                    diff.changed.insert(
+                        keychain.clone(),
+                        (descriptor.clone(), other_descriptor.clone()),
+                    );
+                }
+                Some(_) => {}
+            }
+        }
+        for (keychain, descriptor) in &other.descriptors {
+            if !self.descriptors.contains_key(keychain) {
+                diff.only_in_other.insert(keychain.clone(), descriptor.clone());
+            }
+        }
+        diff
+    }
+
+    /// Switch the keyring to `network`, e.g. to fix one created for the wrong network.
+    ///
+    /// Every descriptor is revalidated first, and the network is left unchanged if any of them
+    /// has keys for another network.
+    pub fn set_network(&mut self, network: Network) -> Result<(), KeyRingError> {
+        for descriptor in self.descriptors.values() {
+            descriptor
+                .clone()
+                .into_wallet_descriptor(&self.secp, network)
+                .map_err(|e| descriptor_error(network, e))?;
+        }
+        self.network = network;
+        Ok(())
+    }
+
     /// Initial changeset.
     pub fn initial_changeset(&self) -> ChangeSet<K> {
         ChangeSet {
             network: Some(self.network),
             descriptors: self.descriptors.clone(),
+            removed: BTreeSet::default(),
+            multipath_groups: self.multipath_groups.clone(),
+            multipath_paths: self.multipath_paths.clone(),
         }
     }
 
     /// Construct from changeset.
+    ///
+    /// Secret keys aren't persisted, so the keyring is always watch-only until they are added
+    /// back with [`add_keymap`](Self::add_keymap).
     pub fn from_changeset(changeset: ChangeSet<K>) -> Option<Self> {
         Some(Self {
             secp: Secp256k1::new(),
             network: changeset.network?,
             descriptors: changeset.descriptors,
+            multipath_groups: changeset.multipath_groups,
+            multipath_paths: changeset.multipath_paths,
+            keymaps: BTreeMap::default(),
         })
     }
 }
 
+/// Id of a multipath descriptor, shared by the single descriptors it is split into.
+///
+/// [`DescriptorExt::descriptor_id`] derives a script pubkey, which a multipath descriptor doesn't
+/// have, so hash the descriptor string instead.
+fn multipath_group_id(descriptor: &Descriptor<DescriptorPublicKey>) -> Did {
+    Did::from_raw_hash(sha256::Hash::hash(descriptor.to_string().as_bytes()))
+}
+
+/// Check that `descriptor` has no hardened derivation after an xpub, which can't be derived
+/// without the private key.
+///
+/// Such descriptors parse, and [`at_derivation_index`] succeeds as it only substitutes the index,
+/// so they would otherwise only fail once a script pubkey is derived.
+///
+/// [`at_derivation_index`]: Descriptor::at_derivation_index
+fn check_derivable(descriptor: &Descriptor<DescriptorPublicKey>) -> Result<(), KeyRingError> {
+    let derivable = descriptor.for_each_key(|pk| match pk {
+        DescriptorPublicKey::Single(_) => true,
+        DescriptorPublicKey::XPub(xkey) => {
+            xkey.wildcard != Wildcard::Hardened
+                && xkey.derivation_path.into_iter().all(ChildNumber::is_normal)
+        }
+        DescriptorPublicKey::MultiXPub(xkey) => {
+            xkey.wildcard != Wildcard::Hardened
+                && xkey
+                    .derivation_paths
+                    .paths()
+                    .iter()
+                    .all(|path| path.into_iter().all(ChildNumber::is_normal))
+        }
+    });
+    if derivable {
+        Ok(())
+    } else {
+        Err(KeyRingError::UnusableDescriptor)
+    }
+}
+
+/// Map an error from parsing a descriptor for `network` to a [`KeyRingError`].
+fn descriptor_error(network: Network, err: DescriptorError) -> KeyRingError {
+    match err {
+        // Keys only encode whether they belong to mainnet or a test network, so the kind found is
+        // the opposite of the one expected.
+        DescriptorError::Key(KeyError::InvalidNetwork) => KeyRingError::NetworkMismatch {
+            expected: network,
+            found: match NetworkKind::from(network) {
+                NetworkKind::Main => NetworkKind::Test,
+                NetworkKind::Test => NetworkKind::Main,
+            },
+        },
+        err => err.into(),
+    }
+}
+
+/// Differences between the descriptors of two keyrings, see [`KeyRing::diff`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRingDiff<K> {
+    /// Keychains only present in `self`.
+    pub only_in_self: BTreeMap<K, Descriptor<DescriptorPublicKey>>,
+    /// Keychains only present in `other`.
+    pub only_in_other: BTreeMap<K, Descriptor<DescriptorPublicKey>>,
+    /// Keychains present in both with different descriptors, as `(self, other)`.
+    pub changed: BTreeMap<K, (Descriptor<DescriptorPublicKey>, Descriptor<DescriptorPublicKey>)>,
+}
+
+impl<K> Default for KeyRingDiff<K> {
+    fn default() -> Self {
+        Self {
+            only_in_self: BTreeMap::default(),
+            only_in_other: BTreeMap::default(),
+            changed: BTreeMap::default(),
+        }
+    }
+}
+
+impl<K> KeyRingDiff<K> {
+    /// Whether both keyrings have the same descriptors.
+    pub fn is_empty(&self) -> bool {
+        self.only_in_self.is_empty() && self.only_in_other.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Builder for a [`KeyRing`] that reports invalid descriptors instead of panicking.
+///
+/// Descriptors are validated as they are added, and [`build`](Self::build) returns the first
+/// error encountered.
+#[derive(Debug)]
+pub struct KeyRingBuilder<K> {
+    keyring: KeyRing<K>,
+    error: Option<KeyRingError>,
+}
+
+impl<K> KeyRingBuilder<K>
+where
+    K: Ord + Clone,
+{
+    /// Add a single descriptor for `keychain`.
+    pub fn descriptor(mut self, keychain: K, descriptor: impl IntoWalletDescriptor) -> Self {
+        if self.error.is_none() {
+            self.error = self
+                .keyring
+                .add_descriptor_validated(keychain, descriptor)
+                .err();
+        }
+        self
+    }
+
+    /// Finish building the [`KeyRing`].
+    ///
+    /// Fails with the first invalid descriptor, or [`KeyRingError::EmptyKeyRing`] if no
+    /// descriptors were added.
+    pub fn build(self) -> Result<KeyRing<K>, KeyRingError> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        if self.keyring.descriptors.is_empty() {
+            return Err(KeyRingError::EmptyKeyRing);
+        }
+        Ok(self.keyring)
+    }
+}
+
+impl KeyRingBuilder<Did> {
+    /// Add a multipath descriptor, keyed by the descriptor id of each single descriptor.
+    pub fn multipath_descriptor(mut self, descriptor: impl IntoWalletDescriptor) -> Self {
+        if self.error.is_none() {
+            self.error = self
+                .keyring
+                .add_multipath_descriptor_validated(descriptor)
+                .err();
+        }
+        self
+    }
+}
+
 impl KeyRing<Did> {
-    /// Add multipath descriptor with validation
+    /// Expand a multipath descriptor for `network` into its single descriptors and their ids,
+    /// without adding them to a keyring.
+    ///
+    /// This returns what [`add_multipath_descriptor_validated`] would add, e.g. to confirm the
+    /// expansion with the user first.
+    ///
+    /// [`add_multipath_descriptor_validated`]: Self::add_multipath_descriptor_validated
+    pub fn preview_multipath(
+        descriptor: impl IntoWalletDescriptor,
+        network: Network,
+    ) -> Result<Vec<(Did, Descriptor<DescriptorPublicKey>)>, KeyRingError> {
+        let secp = Secp256k1::new();
+        let (descriptor, _) = descriptor
+            .into_wallet_descriptor(&secp, network)
+            .map_err(|e| descriptor_error(network, e))?;
+
+        if !descriptor.is_multipath() {
+            return Err(KeyRingError::SingleDescriptorNotAllowed);
+        }
+        check_derivable(&descriptor)?;
+
+        descriptor
+            .into_single_descriptors()
+            .map_err(|e| KeyRingError::DescriptorParsing(e.to_string()))?
+            .into_iter()
+            .map(|descriptor| {
+                descriptor
+                    .at_derivation_index(0)
+                    .map_err(|_| KeyRingError::AddressGeneration)?;
+                Ok((descriptor.descriptor_id(), descriptor))
+            })
+            .collect()
+    }
+
+    /// Add multipath descriptor with validation, returning the ids of the receive and change
+    /// descriptors.
+    ///
+    /// Fails with [`KeyRingError::MultipathPathCount`] if the descriptor doesn't have exactly two
+    /// paths.
     pub fn add_multipath_descriptor_validated(
         &mut self,
         descriptor: impl IntoWalletDescriptor
-    ) -> Result<(), KeyRingError> {
-        let (descriptor, _) = descriptor
+    ) -> Result<(Did, Did), KeyRingError> {
+        let (descriptor, keymap) = descriptor
             .into_wallet_descriptor(&self.secp, self.network)
-            .map_err(|_| KeyRingError::DescriptorParsing)?;
+            .map_err(|e| descriptor_error(self.network, e))?;
 
         if !descriptor.is_multipath() {
             return Err(KeyRingError::SingleDescriptorNotAllowed);
         }
+        check_derivable(&descriptor)?;
 
+        let group = multipath_group_id(&descriptor);
         let descriptors = descriptor
             .into_single_descriptors()
-            .map_err(|_| KeyRingError::DescriptorParsing)?;
-
-        for descriptor in descriptors {
-            let did = descriptor.descriptor_id();
+            .map_err(|e| KeyRingError::DescriptorParsing(e.to_string()))?;
+        if descriptors.len() != 2 {
+            return Err(KeyRingError::MultipathPathCount(descriptors.len()));
+        }
 
-            if self.descriptors.contains_key(&did) {
+        for descriptor in &descriptors {
+            if self.descriptors.contains_key(&descriptor.descriptor_id()) {
                 return Err(KeyRingError::DuplicateDescriptor);
             }
 
             // Validate we can derive a script pubkey
             descriptor.at_derivation_index(0)
                 .map_err(|_| KeyRingError::AddressGeneration)?;
+        }
 
+        let mut dids = Vec::with_capacity(2);
+        for (path, descriptor) in (0..).zip(descriptors) {
+            let did = descriptor.descriptor_id();
+            self.multipath_groups.insert(did, group);
+            self.multipath_paths.insert(did, path);
+            self.insert_keymap(did, keymap.clone());
             self.descriptors.insert(did, descriptor);
+            dids.push(did);
         }
 
-        Ok(())
+        Ok((dids[0], dids[1]))
     }
 
-    /// Add multipath descriptor.
-    pub fn add_multipath_descriptor(&mut self, descriptor: impl IntoWalletDescriptor) {
-        let descriptor = descriptor
+    /// Add multipath descriptor, returning the ids of the receive and change descriptors.
+    ///
+    /// Panics if the descriptor doesn't have exactly two paths.
+    pub fn add_multipath_descriptor(&mut self, descriptor: impl IntoWalletDescriptor) -> (Did, Did) {
+        let (descriptor, keymap) = descriptor
             .into_wallet_descriptor(&self.secp, self.network)
-            .expect("err: invalid descriptor")
-            .0;
+            .expect("err: invalid descriptor");
         assert!(
             descriptor.is_multipath(),
             "err: Use `add_descriptor` instead"
         );
+        check_derivable(&descriptor).expect("err: hardened derivation after an xpub");
+        let group = multipath_group_id(&descriptor);
         let descriptors = descriptor
             .into_single_descriptors()
             .expect("err: invalid descriptor");
-        for descriptor in descriptors {
+        assert_eq!(descriptors.len(), 2, "err: expected a `<0;1>` multipath descriptor");
+        let mut dids = Vec::with_capacity(2);
+        for (path, descriptor) in (0..).zip(descriptors) {
             let did = descriptor.descriptor_id();
+            self.multipath_groups.insert(did, group);
+            self.multipath_paths.insert(did, path);
+            self.insert_keymap(did, keymap.clone());
             self.descriptors.insert(did, descriptor);
+            dids.push(did);
         }
+        (dids[0], dids[1])
     }
 }
 
+/// Translates the `.../0/*` receive path of each key into the `.../1/*` change path.
+struct ChangePathTranslator;
+
+impl Translator<DescriptorPublicKey, DescriptorPublicKey, KeyRingError> for ChangePathTranslator {
+    fn pk(&mut self, pk: &DescriptorPublicKey) -> Result<DescriptorPublicKey, KeyRingError> {
+        match pk {
+            DescriptorPublicKey::XPub(xkey) if xkey.wildcard == Wildcard::Unhardened => {
+                match xkey.derivation_path.as_ref().split_last() {
+                    Some((ChildNumber::Normal { index: 0 }, parent)) => {
+                        let mut xkey = xkey.clone();
+                        xkey.derivation_path = DerivationPath::from(parent.to_vec())
+                            .child(ChildNumber::Normal { index: 1 });
+                        Ok(DescriptorPublicKey::XPub(xkey))
+                    }
+                    _ => Err(KeyRingError::NonStandardDerivation),
+                }
+            }
+            _ => Err(KeyRingError::NonStandardDerivation),
+        }
+    }
+
+    translate_hash_clone!(DescriptorPublicKey, DescriptorPublicKey, KeyRingError);
+}
+
 /// Represents changes to the `KeyRing`.
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+///
+/// Descriptors are serialized in their canonical string form, including key origins and the
+/// checksum, so they survive a round-trip through formats such as JSON unchanged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(
+        deserialize = "K: Ord + Deserialize<'de>",
+        serialize = "K: Ord + Serialize",
+    ))
+)]
 pub struct ChangeSet<K: Ord> {
     /// Network.
     pub network: Option<Network>,
     /// Added descriptors.
     pub descriptors: BTreeMap<K, Descriptor<DescriptorPublicKey>>,
+    /// Removed keychains.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub removed: BTreeSet<K>,
+    /// Id of the multipath descriptor each keychain was split from.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub multipath_groups: BTreeMap<K, Did>,
+    /// Index of the multipath path each keychain was split from.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub multipath_paths: BTreeMap<K, u32>,
 }
 
 impl<K: Ord> Default for ChangeSet<K> {
@@ -204,7 +764,32 @@ impl<K: Ord> Default for ChangeSet<K> {
         Self {
             network: None,
             descriptors: BTreeMap::default(),
+            removed: BTreeSet::default(),
+            multipath_groups: BTreeMap::default(),
+            multipath_paths: BTreeMap::default(),
+        }
+    }
+}
+
+impl<K: Ord> ChangeSet<K> {
+    /// Merge `other` into `self`, failing if it assigns a different descriptor to a keychain that
+    /// already has one.
+    ///
+    /// [`Merge::merge`] keeps the original descriptor in that case. On error `self` is left
+    /// unchanged.
+    pub fn try_merge(&mut self, other: Self) -> Result<(), KeyRingError> {
+        let conflict = other.descriptors.iter().any(|(keychain, descriptor)| {
+            !other.removed.contains(keychain)
+                && self
+                    .descriptors
+                    .get(keychain)
+                    .map_or(false, |existing| existing != descriptor)
+        });
+        if conflict {
+            return Err(KeyRingError::DuplicateDescriptor);
         }
+        self.merge(other);
+        Ok(())
     }
 }
 
@@ -213,10 +798,336 @@ impl<K: Ord> Merge for ChangeSet<K> {
         if self.network.is_none() {
             self.network = other.network;
         }
-        self.descriptors.extend(other.descriptors);
+        // a keychain is either added or removed, whichever happened last
+        for keychain in other.removed {
+            self.descriptors.remove(&keychain);
+            self.multipath_groups.remove(&keychain);
+            self.multipath_paths.remove(&keychain);
+            self.removed.insert(keychain);
+        }
+        // descriptors are append-only, so never overwrite the one already assigned to a keychain
+        for (keychain, descriptor) in other.descriptors {
+            self.removed.remove(&keychain);
+            self.descriptors.entry(keychain).or_insert(descriptor);
+        }
+        for (keychain, group) in other.multipath_groups {
+            self.multipath_groups.entry(keychain).or_insert(group);
+        }
+        for (keychain, path) in other.multipath_paths {
+            self.multipath_paths.entry(keychain).or_insert(path);
+        }
     }
 
     fn is_empty(&self) -> bool {
-        self.network.is_none() && self.descriptors.is_empty()
+        self.network.is_none()
+            && self.descriptors.is_empty()
+            && self.removed.is_empty()
+            && self.multipath_groups.is_empty()
+            && self.multipath_paths.is_empty()
+    }
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::multi_keychain::test_utils::*;
+
+    /// Account xpub without an origin, to build descriptors with arbitrary derivation steps.
+    const TPUB: &str = "tpubD6NzVbkrYhZ4WaWSyoBvQwbpLkojyoTZPRsgXELWz3Popb3qkjcJyJUGLnL4qHHoQvao8ESaAstxYSnhyswJ76uZPStJRJCTKvosUCJZL5B";
+
+    #[test]
+    fn iter_yields_descriptors_in_keychain_order() {
+        let mut keyring = KeyRing::new(Network::Signet);
+        keyring
+            .add_descriptor_validated(Keychain::Internal, WPKH_CHANGE)
+            .unwrap();
+        keyring
+            .add_descriptor_validated(Keychain::External, WPKH_RECEIVE)
+            .unwrap();
+
+        let keychains: Vec<_> = keyring.iter().map(|(keychain, _)| *keychain).collect();
+        assert_eq!(keychains, [Keychain::External, Keychain::Internal]);
+        for (keychain, descriptor) in keyring.iter() {
+            assert_eq!(Some(descriptor), keyring.get_descriptor(keychain));
+        }
+
+        let reversed: Vec<_> = keyring.iter().rev().map(|(keychain, _)| *keychain).collect();
+        assert_eq!(reversed, [Keychain::Internal, Keychain::External]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn derive_change_keychain_uses_change_path() {
+        let mut keyring = KeyRing::new(Network::Signet);
+        keyring
+            .add_descriptor_validated(Keychain::External, WPKH_RECEIVE)
+            .unwrap();
+        keyring
+            .derive_change_keychain(Keychain::External, Keychain::Internal)
+            .unwrap();
+
+        let mut expected = KeyRing::new(Network::Signet);
+        expected
+            .add_descriptor_validated(Keychain::Internal, WPKH_CHANGE)
+            .unwrap();
+        assert_eq!(
+            keyring.get_descriptor(&Keychain::Internal),
+            expected.get_descriptor(&Keychain::Internal),
+        );
+        let change = keyring.get_descriptor(&Keychain::Internal).unwrap();
+        assert!(change.to_string().contains("/1/*"));
+    }
+
+    #[test]
+    fn derive_change_keychain_rejects_non_receive_path() {
+        let mut keyring = KeyRing::new(Network::Signet);
+        keyring
+            .add_descriptor_validated(Keychain::External, WPKH_CHANGE)
+            .unwrap();
+        assert_eq!(
+            keyring.derive_change_keychain(Keychain::External, Keychain::Internal),
+            Err(KeyRingError::NonStandardDerivation),
+        );
+        assert!(!keyring.contains_keychain(&Keychain::Internal));
+
+        let mut keyring = KeyRing::new(Network::Signet);
+        keyring
+            .add_descriptor_validated(
+                Keychain::External,
+                "wpkh(cVt4o7BGAig1UXywgGSmARhxMdzP5qvQsxKkSsc1XEkw3tDTQFpy)",
+            )
+            .unwrap();
+        assert_eq!(
+            keyring.derive_change_keychain(Keychain::External, Keychain::Internal),
+            Err(KeyRingError::NonStandardDerivation),
+        );
+    }
+
+    #[test]
+    fn derive_change_keychain_requires_receive_keychain() {
+        let mut keyring = KeyRing::<Keychain>::new(Network::Signet);
+        assert_eq!(
+            keyring.derive_change_keychain(Keychain::External, Keychain::Internal),
+            Err(KeyRingError::KeychainNotFound),
+        );
+    }
+
+    #[test]
+    fn add_descriptor_validated_reports_network_kind() {
+        let mut keyring = KeyRing::new(Network::Bitcoin);
+        assert_eq!(
+            keyring.add_descriptor_validated(Keychain::External, WPKH_RECEIVE),
+            Err(KeyRingError::NetworkMismatch {
+                expected: Network::Bitcoin,
+                found: NetworkKind::Test,
+            }),
+        );
+        assert!(keyring.is_empty());
+    }
+
+    #[test]
+    fn hardened_step_after_xpub_is_unusable() {
+        let mut keyring = KeyRing::new(Network::Signet);
+        for descriptor in [
+            alloc::format!("wpkh({TPUB}/0'/*)"),
+            alloc::format!("wpkh({TPUB}/0/*')"),
+        ] {
+            assert_eq!(
+                keyring.add_descriptor_validated(Keychain::External, descriptor.as_str()),
+                Err(KeyRingError::UnusableDescriptor)
+            );
+        }
+        assert_eq!(
+            keyring.add_multipath_descriptor_split(
+                Keychain::External,
+                Keychain::Internal,
+                alloc::format!("wpkh({TPUB}/0'/<0;1>/*)").as_str(),
+            ),
+            Err(KeyRingError::UnusableDescriptor)
+        );
+        assert!(keyring.is_empty());
+
+        let usable = alloc::format!("wpkh({TPUB}/0/*)");
+        keyring
+            .add_descriptor_validated(Keychain::External, usable.as_str())
+            .unwrap();
+    }
+
+    #[test]
+    fn export_core_descriptors_marks_active_and_internal() {
+        const SINGLE_KEY: &str = "wpkh(cVt4o7BGAig1UXywgGSmARhxMdzP5qvQsxKkSsc1XEkw3tDTQFpy)";
+        let mut keyring = KeyRing::new(Network::Signet);
+        let (receive, change) = keyring.add_multipath_descriptor(WPKH_MULTIPATH);
+        let single = Did::from_byte_array([1; 32]);
+        keyring.add_descriptor(single, SINGLE_KEY);
+
+        for (keychain, request) in keyring.keychains().zip(keyring.export_core_descriptors()) {
+            let request: serde_json::Value = serde_json::from_str(&request).unwrap();
+            let descriptor = keyring.get_descriptor(keychain).unwrap();
+            assert_eq!(request["desc"], descriptor.to_string());
+            assert!(descriptor.to_string().contains('#'));
+            assert_eq!(request["timestamp"], 0);
+            assert_eq!(request["internal"], *keychain == change);
+            if *keychain == single {
+                assert!(request.get("active").is_none());
+                assert!(request.get("range").is_none());
+            } else {
+                assert!(*keychain == receive || *keychain == change);
+                assert_eq!(request["active"], true);
+                assert_eq!(request["range"], serde_json::json!([0, 999]));
+            }
+        }
+        assert_eq!(keyring.export_core_descriptors().len(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "hardened derivation after an xpub")]
+    fn add_descriptor_panics_on_hardened_step_after_xpub() {
+        let mut keyring = KeyRing::new(Network::Signet);
+        keyring.add_descriptor(Keychain::External, alloc::format!("wpkh({TPUB}/0'/*)").as_str());
+    }
+
+    #[test]
+    #[should_panic(expected = "hardened derivation after an xpub")]
+    fn add_multipath_descriptor_panics_on_hardened_step_after_xpub() {
+        let mut keyring = KeyRing::new(Network::Signet);
+        keyring.add_multipath_descriptor(alloc::format!("wpkh({TPUB}/0'/<0;1>/*)").as_str());
+    }
+
+    fn multipath_changeset() -> (ChangeSet<Did>, Did, Did) {
+        let mut keyring = KeyRing::new(Network::Signet);
+        let (receive, change) = keyring.add_multipath_descriptor(WPKH_MULTIPATH);
+        (keyring.initial_changeset(), receive, change)
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn changeset_json_round_trip() {
+        let (changeset, receive, change) = multipath_changeset();
+        assert_eq!(changeset.multipath_paths[&receive], 0);
+        assert_eq!(changeset.multipath_paths[&change], 1);
+
+        let json = serde_json::to_string(&changeset).unwrap();
+        let decoded: ChangeSet<Did> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, changeset);
+
+        // the split descriptors keep their key origin
+        for descriptor in decoded.descriptors.values() {
+            assert!(descriptor.to_string().starts_with("wpkh([e273fe42/84'/1'/0']"));
+        }
+        let keyring = KeyRing::from_changeset(decoded).unwrap();
+        let group = keyring.multipath_group(&receive).unwrap();
+        assert_eq!(keyring.receive_change_pair(group), Some((receive, change)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn changeset_json_defaults_missing_fields() {
+        let (changeset, _, _) = multipath_changeset();
+        let mut json = serde_json::to_value(&changeset).unwrap();
+        let object = json.as_object_mut().unwrap();
+        object.remove("removed");
+        object.remove("multipath_groups");
+        object.remove("multipath_paths");
+
+        let decoded: ChangeSet<Did> = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded.descriptors, changeset.descriptors);
+        assert!(decoded.removed.is_empty());
+        assert!(decoded.multipath_groups.is_empty());
+        assert!(decoded.multipath_paths.is_empty());
+    }
+
+    #[test]
+    fn changeset_merge_keeps_first_descriptor() {
+        let mut receive = KeyRing::new(Network::Signet);
+        receive
+            .add_descriptor_validated(Keychain::External, WPKH_RECEIVE)
+            .unwrap();
+        let mut change = KeyRing::new(Network::Signet);
+        change
+            .add_descriptor_validated(Keychain::External, WPKH_CHANGE)
+            .unwrap();
+
+        let mut changeset = receive.initial_changeset();
+        changeset.merge(change.initial_changeset());
+        assert_eq!(
+            changeset.descriptors.get(&Keychain::External),
+            receive.get_descriptor(&Keychain::External)
+        );
+    }
+
+    #[test]
+    fn changeset_merge_applies_removal_and_re_adding() {
+        let (changeset, receive, change) = multipath_changeset();
+
+        let mut merged = changeset.clone();
+        merged.merge(ChangeSet {
+            removed: [receive].into(),
+            ..Default::default()
+        });
+        assert!(!merged.descriptors.contains_key(&receive));
+        assert!(!merged.multipath_groups.contains_key(&receive));
+        assert!(!merged.multipath_paths.contains_key(&receive));
+        assert!(merged.descriptors.contains_key(&change));
+
+        // adding the keychain back afterwards undoes the removal
+        merged.merge(changeset.clone());
+        assert!(merged.removed.is_empty());
+        assert_eq!(merged.descriptors, changeset.descriptors);
+        assert_eq!(merged.multipath_groups, changeset.multipath_groups);
+        assert_eq!(merged.multipath_paths, changeset.multipath_paths);
+    }
+
+    #[test]
+    fn changeset_try_merge_rejects_conflicting_descriptor() {
+        let mut receive = KeyRing::new(Network::Signet);
+        receive
+            .add_descriptor_validated(Keychain::External, WPKH_RECEIVE)
+            .unwrap();
+        let mut change = KeyRing::new(Network::Signet);
+        change
+            .add_descriptor_validated(Keychain::External, WPKH_CHANGE)
+            .unwrap();
+
+        let mut changeset = receive.initial_changeset();
+        assert_eq!(
+            changeset.try_merge(change.initial_changeset()),
+            Err(KeyRingError::DuplicateDescriptor)
+        );
+        assert_eq!(changeset, receive.initial_changeset());
+
+        // the same descriptor again isn't a conflict
+        changeset.try_merge(receive.initial_changeset()).unwrap();
+        assert_eq!(changeset, receive.initial_changeset());
+
+        // nor is one that replaces a keychain removed in the same changeset
+        let mut replacement = change.initial_changeset();
+        replacement.removed.insert(Keychain::External);
+        changeset.try_merge(replacement).unwrap();
+        assert_eq!(
+            changeset.descriptors.get(&Keychain::External),
+            change.get_descriptor(&Keychain::External)
+        );
+    }
+
+    #[test]
+    fn add_keymap_checks_descriptor() {
+        let mut keyring = KeyRing::new(Network::Signet);
+        keyring
+            .add_descriptor_validated(Keychain::External, WPKH_RECEIVE)
+            .unwrap();
+        let mut keyring = KeyRing::from_changeset(keyring.initial_changeset()).unwrap();
+        assert!(keyring.is_watch_only());
+
+        assert_eq!(
+            keyring.add_keymap(Keychain::External, WPKH_CHANGE),
+            Err(KeyRingError::KeymapMismatch)
+        );
+        assert_eq!(
+            keyring.add_keymap(Keychain::Internal, WPKH_CHANGE),
+            Err(KeyRingError::KeychainNotFound)
+        );
+        assert!(keyring.is_watch_only());
+
+        keyring.add_keymap(Keychain::External, WPKH_RECEIVE).unwrap();
+        assert!(keyring.has_secret(&Keychain::External));
+    }
+}
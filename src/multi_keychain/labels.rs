@@ -0,0 +1,230 @@
+//! Address and transaction labels, exportable as [BIP-329] records.
+//!
+//! [BIP-329]: https://github.com/bitcoin/bips/blob/master/bip-0329.mediawiki
+
+use alloc::string::String;
+use core::fmt::{self, Write};
+
+use bdk_chain::Merge;
+use bitcoin::Txid;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::bdk_chain;
+use crate::collections::BTreeMap;
+
+/// Labels attached to addresses and transactions.
+///
+/// This is both the wallet's label state and its changeset. Merging keeps the label of `other`
+/// where both have one, so the last write wins.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(
+        deserialize = "K: Ord + Deserialize<'de>",
+        serialize = "K: Ord + Serialize",
+    ))
+)]
+pub struct Labels<K: Ord> {
+    /// Address labels by keychain and derivation index.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub addresses: BTreeMap<K, BTreeMap<u32, String>>,
+    /// Transaction labels.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub txs: BTreeMap<Txid, String>,
+}
+
+impl<K: Ord> Default for Labels<K> {
+    fn default() -> Self {
+        Self {
+            addresses: BTreeMap::default(),
+            txs: BTreeMap::default(),
+        }
+    }
+}
+
+impl<K: Ord> Merge for Labels<K> {
+    fn merge(&mut self, other: Self) {
+        for (keychain, labels) in other.addresses {
+            self.addresses.entry(keychain).or_default().extend(labels);
+        }
+        self.txs.extend(other.txs);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.addresses.is_empty() && self.txs.is_empty()
+    }
+}
+
+/// Append a BIP-329 JSON record of `label` for `reference` of type `kind`, e.g. `addr` or `tx`,
+/// followed by a newline.
+pub(crate) fn write_bip329_record(
+    out: &mut String,
+    kind: &str,
+    reference: impl fmt::Display,
+    label: &str,
+) {
+    let _ = write!(out, "{{\"type\":\"{}\",\"ref\":\"{}\",\"label\":", kind, reference);
+    write_json_string(out, label);
+    out.push_str("}\n");
+}
+
+/// Append `value` to `out` as a quoted JSON string, escaping it as needed.
+pub(crate) fn write_json_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        let _ = match c {
+            '"' => out.write_str("\\\""),
+            '\\' => out.write_str("\\\\"),
+            '\n' => out.write_str("\\n"),
+            '\r' => out.write_str("\\r"),
+            '\t' => out.write_str("\\t"),
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32),
+            c => out.write_char(c),
+        };
+    }
+    out.push('"');
+}
+
+/// A parsed BIP-329 record: its `type`, `ref` and `label`, if it has one.
+pub(crate) type Bip329Record = (String, String, Option<String>);
+
+/// Parse a BIP-329 JSON record, a flat JSON object on a single line.
+///
+/// Values other than strings, such as the `spendable` flag of outputs, are skipped. Returns `None`
+/// if `line` isn't a JSON object or lacks a `type` or `ref`.
+pub(crate) fn parse_bip329_record(line: &str) -> Option<Bip329Record> {
+    let mut parser = JsonParser {
+        chars: line.chars().peekable(),
+    };
+    let mut fields = parser.object()?;
+    let kind = fields.remove("type")??;
+    let reference = fields.remove("ref")??;
+    let label = fields.remove("label").flatten();
+    Some((kind, reference, label))
+}
+
+/// Just enough of a JSON parser for BIP-329 records.
+struct JsonParser<'a> {
+    chars: core::iter::Peekable<core::str::Chars<'a>>,
+}
+
+impl JsonParser<'_> {
+    fn skip_whitespace(&mut self) {
+        while matches!(self.chars.peek(), Some(c) if c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    /// Parse an object that makes up the rest of the input, with `None` for non-string values.
+    fn object(&mut self) -> Option<BTreeMap<String, Option<String>>> {
+        let mut fields = BTreeMap::new();
+        self.skip_whitespace();
+        if self.chars.next()? != '{' {
+            return None;
+        }
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&'}') {
+            self.chars.next();
+        } else {
+            loop {
+                self.skip_whitespace();
+                let key = self.string()?;
+                self.skip_whitespace();
+                if self.chars.next()? != ':' {
+                    return None;
+                }
+                self.skip_whitespace();
+                let value = if self.chars.peek() == Some(&'"') {
+                    Some(self.string()?)
+                } else {
+                    self.scalar()?;
+                    None
+                };
+                fields.insert(key, value);
+                self.skip_whitespace();
+                match self.chars.next()? {
+                    ',' => continue,
+                    '}' => break,
+                    _ => return None,
+                }
+            }
+        }
+        self.skip_whitespace();
+        if self.chars.next().is_some() {
+            return None;
+        }
+        Some(fields)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        if self.chars.next()? != '"' {
+            return None;
+        }
+        let mut value = String::new();
+        loop {
+            let c = match self.chars.next()? {
+                '"' => return Some(value),
+                '\\' => match self.chars.next()? {
+                    '"' => '"',
+                    '\\' => '\\',
+                    '/' => '/',
+                    'b' => '\u{8}',
+                    'f' => '\u{c}',
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    'u' => self.unicode_escape()?,
+                    _ => return None,
+                },
+                c if c.is_control() => return None,
+                c => c,
+            };
+            value.push(c);
+        }
+    }
+
+    /// Parse the code point of a `\u` escape, including the low half of a surrogate pair.
+    fn unicode_escape(&mut self) -> Option<char> {
+        let high = self.hex4()?;
+        if !(0xd800..0xdc00).contains(&high) {
+            return char::from_u32(high);
+        }
+        if self.chars.next()? != '\\' || self.chars.next()? != 'u' {
+            return None;
+        }
+        let low = self.hex4()?;
+        if !(0xdc00..0xe000).contains(&low) {
+            return None;
+        }
+        char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00))
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..4 {
+            value = value * 16 + self.chars.next()?.to_digit(16)?;
+        }
+        Some(value)
+    }
+
+    /// Skip `true`, `false`, `null` or a number.
+    fn scalar(&mut self) -> Option<()> {
+        let mut token = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !(c.is_ascii_alphanumeric() || matches!(c, '-' | '+' | '.')) {
+                break;
+            }
+            token.push(c);
+            self.chars.next();
+        }
+        let is_number = token.starts_with(|c: char| c == '-' || c.is_ascii_digit())
+            && token.parse::<f64>().is_ok();
+        if is_number || matches!(token.as_str(), "true" | "false" | "null") {
+            Some(())
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! Wallet persistence.
+
+#[cfg(feature = "rusqlite")]
+use crate::bdk_chain::{rusqlite, DescriptorId};
+use crate::multi_keychain::ChangeSet;
+
+/// A backend that stores the [`ChangeSet`]s of a [`Wallet`](crate::multi_keychain::Wallet).
+///
+/// Implement this to persist the wallet somewhere other than SQLite, and pass it to
+/// [`Wallet::load`](crate::multi_keychain::Wallet::load) and
+/// [`Wallet::persist`](crate::multi_keychain::Wallet::persist).
+pub trait WalletPersister<K: Ord> {
+    /// Error returned by the backend.
+    type Error;
+
+    /// Initialize the backend and return the aggregate of every changeset persisted so far, or
+    /// `None` if nothing has been persisted yet.
+    fn initialize(&mut self) -> Result<Option<ChangeSet<K>>, Self::Error>;
+
+    /// Persist `changeset`, which is merged with what was persisted before.
+    fn persist(&mut self, changeset: &ChangeSet<K>) -> Result<(), Self::Error>;
+}
+
+/// Boxed future returned by [`AsyncWalletPersister`] methods.
+#[cfg(feature = "async")]
+pub type FutureResult<'a, T, E> =
+    core::pin::Pin<alloc::boxed::Box<dyn core::future::Future<Output = Result<T, E>> + Send + 'a>>;
+
+/// Async version of [`WalletPersister`], e.g. for a database with an async client.
+///
+/// The methods return boxed futures so the trait can be used without `async fn` in traits. Pass
+/// it to [`Wallet::load_async`](crate::multi_keychain::Wallet::load_async) and
+/// [`Wallet::persist_async`](crate::multi_keychain::Wallet::persist_async).
+#[cfg(feature = "async")]
+pub trait AsyncWalletPersister<K: Ord> {
+    /// Error returned by the backend.
+    type Error;
+
+    /// Initialize the backend and return the aggregate of every changeset persisted so far, or
+    /// `None` if nothing has been persisted yet.
+    fn initialize<'a>(&'a mut self) -> FutureResult<'a, Option<ChangeSet<K>>, Self::Error>
+    where
+        Self: 'a;
+
+    /// Persist `changeset`, which is merged with what was persisted before.
+    fn persist<'a>(&'a mut self, changeset: &'a ChangeSet<K>) -> FutureResult<'a, (), Self::Error>
+    where
+        Self: 'a;
+}
+
+#[cfg(feature = "rusqlite")]
+impl WalletPersister<DescriptorId> for rusqlite::Connection {
+    type Error = rusqlite::Error;
+
+    fn initialize(&mut self) -> Result<Option<ChangeSet<DescriptorId>>, Self::Error> {
+        let tx = self.transaction()?;
+        let changeset = ChangeSet::initialize(&tx)?;
+        tx.commit()?;
+        Ok(changeset)
+    }
+
+    fn persist(&mut self, changeset: &ChangeSet<DescriptorId>) -> Result<(), Self::Error> {
+        let tx = self.transaction()?;
+        changeset.persist_to_sqlite(&tx)?;
+        tx.commit()
+    }
+}
@@ -0,0 +1,170 @@
+//! Helpers shared by the unit tests.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use bitcoin::hashes::Hash;
+use bitcoin::{
+    absolute, transaction, Amount, BlockHash, Network, OutPoint, ScriptBuf, Transaction, TxIn,
+    TxOut, Txid,
+};
+
+use crate::bdk_chain::{BlockId, ConfirmationBlockTime, TxUpdate};
+use crate::multi_keychain::{KeyRing, Update, Wallet};
+
+/// Receive descriptor of a single-sig `wpkh` account, with its private key.
+pub(crate) const WPKH_RECEIVE: &str = "wpkh(tprv8ZgxMBicQKsPdy6LMhUtFHAgpocR8GC6QmwMSFpZs7h6Eziw3SpThFfczTDh5rW2krkqffa11UpX3XkeTTB2FvzZKWXqPY54Y6Rq4AQ5R8L/84'/1'/0'/0/*)";
+/// Change descriptor of the [`WPKH_RECEIVE`] account.
+pub(crate) const WPKH_CHANGE: &str = "wpkh(tprv8ZgxMBicQKsPdy6LMhUtFHAgpocR8GC6QmwMSFpZs7h6Eziw3SpThFfczTDh5rW2krkqffa11UpX3XkeTTB2FvzZKWXqPY54Y6Rq4AQ5R8L/84'/1'/0'/1/*)";
+/// The [`WPKH_RECEIVE`] account as a watch-only `<0;1>` multipath descriptor.
+///
+/// Multipath descriptors can't have private keys with hardened steps, so this has the account
+/// xpub instead.
+pub(crate) const WPKH_MULTIPATH: &str = "wpkh([e273fe42/84'/1'/0']tpubDCmr3Luq75npLaYmRqqW1rLfSbfpnBXwLwAmUbR333fp95wjCHar3zoc9zSWovZFwrWr53mm3NTVqt6d1Pt6G26uf4etQjc3Pr5Hxe9QEQ2/<0;1>/*)";
+/// Receive descriptor of a single-sig taproot key path account, with its private key.
+pub(crate) const TR_RECEIVE: &str = "tr(tprv8ZgxMBicQKsPd3krDUsBAmtnRsK3rb8u5yi1zhQgMhF1tR8MW7xfE4rnrbbsrbPR52e7rKapu6ztw1jXveJSCGHEriUGZV7mCe88duLp5pj/86'/1'/0'/0/*)";
+
+/// Keychains of the test wallets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) enum Keychain {
+    External,
+    Internal,
+}
+
+/// Signet wallet with the [`WPKH_RECEIVE`] and [`WPKH_CHANGE`] keychains.
+pub(crate) fn new_wallet() -> Wallet<Keychain> {
+    new_wallet_with(&[
+        (Keychain::External, WPKH_RECEIVE),
+        (Keychain::Internal, WPKH_CHANGE),
+    ])
+}
+
+/// Signet wallet with the given keychains.
+pub(crate) fn new_wallet_with(descriptors: &[(Keychain, &str)]) -> Wallet<Keychain> {
+    let mut keyring = KeyRing::new(Network::Signet);
+    for &(keychain, descriptor) in descriptors {
+        keyring
+            .add_descriptor_validated(keychain, descriptor)
+            .expect("valid descriptor");
+    }
+    Wallet::new(keyring)
+}
+
+/// Block id of the test block at `height`, or of the signet genesis block at height 0.
+pub(crate) fn block_id(height: u32) -> BlockId {
+    let hash = if height == 0 {
+        bitcoin::constants::genesis_block(Network::Signet).block_hash()
+    } else {
+        BlockHash::hash(&height.to_le_bytes())
+    };
+    BlockId { height, hash }
+}
+
+/// Extend the wallet's chain with the test block at `height`.
+pub(crate) fn insert_checkpoint<K: Ord + Clone + core::fmt::Debug>(
+    wallet: &mut Wallet<K>,
+    height: u32,
+) -> BlockId {
+    let block_id = block_id(height);
+    wallet.insert_checkpoint(block_id).expect("checkpoint connects");
+    block_id
+}
+
+/// Transaction paying `value` to `script_pubkey` from an output the wallet doesn't know.
+///
+/// Every call spends a different outpoint, so the transactions are all distinct.
+pub(crate) fn funding_tx(script_pubkey: ScriptBuf, value: Amount) -> Transaction {
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    Transaction {
+        version: transaction::Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: alloc::vec![TxIn {
+            previous_output: OutPoint::new(Txid::hash(&n.to_le_bytes()), 0),
+            ..Default::default()
+        }],
+        output: alloc::vec![TxOut {
+            value,
+            script_pubkey,
+        }],
+    }
+}
+
+/// Apply `tx` to the wallet, confirmed in the test block at `height`.
+pub(crate) fn confirm_tx<K: Ord + Clone + core::fmt::Debug>(
+    wallet: &mut Wallet<K>,
+    tx: Transaction,
+    height: u32,
+) -> Txid {
+    let block_id = insert_checkpoint(wallet, height);
+    let txid = tx.compute_txid();
+    let mut tx_update = TxUpdate::default();
+    tx_update.txs.push(Arc::new(tx));
+    tx_update.anchors.insert((
+        ConfirmationBlockTime {
+            block_id,
+            confirmation_time: u64::from(height),
+        },
+        txid,
+    ));
+    wallet
+        .apply_update(Update::from_tx_update(tx_update))
+        .expect("no chain update");
+    txid
+}
+
+/// Receive `value` to the next address of `keychain`, confirmed in the test block at `height`.
+pub(crate) fn receive_confirmed<K: Ord + Clone + core::fmt::Debug>(
+    wallet: &mut Wallet<K>,
+    keychain: K,
+    value: Amount,
+    height: u32,
+) -> OutPoint {
+    let (_, address) = wallet.reveal_next_address(keychain).expect("keychain exists");
+    let txid = confirm_tx(wallet, funding_tx(address.script_pubkey(), value), height);
+    OutPoint::new(txid, 0)
+}
+
+/// Receive `value` to the next address of `keychain` in an unconfirmed transaction last seen at
+/// `seen_at`.
+pub(crate) fn receive_unconfirmed<K: Ord + Clone + core::fmt::Debug>(
+    wallet: &mut Wallet<K>,
+    keychain: K,
+    value: Amount,
+    seen_at: u64,
+) -> OutPoint {
+    let (_, address) = wallet.reveal_next_address(keychain).expect("keychain exists");
+    let tx = funding_tx(address.script_pubkey(), value);
+    let txid = tx.compute_txid();
+    wallet.apply_unconfirmed_txs([(Arc::new(tx), seen_at)]);
+    OutPoint::new(txid, 0)
+}
+
+/// Transaction spending `outpoints` to a single output of `value` paying `script_pubkey`.
+pub(crate) fn spending_tx(
+    outpoints: &[OutPoint],
+    script_pubkey: ScriptBuf,
+    value: Amount,
+) -> Transaction {
+    Transaction {
+        version: transaction::Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: outpoints
+            .iter()
+            .map(|&previous_output| TxIn {
+                previous_output,
+                sequence: bitcoin::Sequence::ENABLE_RBF_NO_LOCKTIME,
+                ..Default::default()
+            })
+            .collect(),
+        output: alloc::vec![TxOut {
+            value,
+            script_pubkey,
+        }],
+    }
+}
+
+/// A script pubkey that doesn't belong to any test wallet.
+pub(crate) fn external_spk() -> ScriptBuf {
+    ScriptBuf::new_p2wpkh(&bitcoin::WPubkeyHash::hash(b"external"))
+}
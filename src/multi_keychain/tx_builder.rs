@@ -1,16 +1,47 @@
-use bitcoin::{Address, Amount, FeeRate, OutPoint, Transaction, TxOut, Psbt};
-use crate::bdk_chain::CanonicalizationParams;
+use bitcoin::address::NetworkUnchecked;
+use bitcoin::psbt;
+use bitcoin::{Address, Amount, Denomination, FeeRate, OutPoint, ScriptBuf, Transaction, TxIn, TxOut, Psbt, Weight, WitnessVersion};
+use miniscript::psbt::PsbtInputExt;
+use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::ops::Deref;
 
-use crate::multi_keychain::{Wallet, errors::{WalletError, TxBuilderError}};
+use crate::bdk_chain::{ChainPosition, ConfirmationBlockTime, KeychainIndexed};
+use crate::collections::{BTreeMap, BTreeSet};
+use crate::multi_keychain::{Wallet, errors::{WalletError, TxBuilderError}, fee::FeeEstimator};
 
 pub struct TxBuilder<'a, K: Ord> {
-    wallet: &'a mut Wallet<K>,
-    recipients: Vec<(Address, Amount)>,
+    wallet: WalletRef<'a, K>,
+    recipients: Vec<(ScriptBuf, Amount)>,
+    /// Whether an address passed to [`add_recipients`](Self::add_recipients) isn't valid for the
+    /// wallet's network, reported by [`finish`](Self::finish).
+    recipient_network_mismatch: bool,
     fee_rate: Option<FeeRate>,
     preferred_keychain: Option<K>,
     drain_wallet: bool,
+    drain_to: Vec<(Address, f64)>,
     utxos: Vec<OutPoint>,
+    unspendable: BTreeSet<OutPoint>,
+    /// Outputs spent by earlier builds of the [`TxBatch`] this builder belongs to.
+    batch_spent: Option<&'a mut BTreeSet<OutPoint>>,
+    change_policy: ChangePolicy,
+    min_confirmations: Option<u32>,
+    candidate_order: Option<Box<dyn Fn(&LocalUtxo<K>, &LocalUtxo<K>) -> Ordering + 'a>>,
+    fee_estimator: Option<&'a dyn FeeEstimator>,
+    fee_target_blocks: Option<u16>,
+    anti_fee_sniping: bool,
+    dust_change_to_fee: bool,
+    single_keychain: bool,
+    max_inputs: Option<usize>,
+    change_to_recipient: Option<usize>,
+    fee_rate_from_history: bool,
+    randomize_change_position: bool,
+    #[cfg(feature = "std")]
+    rng: Option<&'a mut dyn bitcoin::secp256k1::rand::RngCore>,
+    label: Option<String>,
+    message: Option<String>,
 }
 
 impl<'a, K> TxBuilder<'a, K>
@@ -18,51 +49,342 @@ where
     K: core::fmt::Debug + Clone + Ord,
 {
     pub fn new(wallet: &'a mut Wallet<K>) -> Self {
+        Self::with_wallet(WalletRef::Mut(wallet))
+    }
+
+    /// Builder over a shared wallet, used to estimate fees. Building never reveals the change
+    /// address, so the wallet is left untouched.
+    pub(crate) fn new_estimate(wallet: &'a Wallet<K>) -> Self {
+        Self::with_wallet(WalletRef::Shared(wallet))
+    }
+
+    fn with_wallet(wallet: WalletRef<'a, K>) -> Self {
         Self {
             wallet,
             recipients: Vec::new(),
+            recipient_network_mismatch: false,
             fee_rate: None,
             preferred_keychain: None,
             drain_wallet: false,
+            drain_to: Vec::new(),
             utxos: Vec::new(),
+            unspendable: BTreeSet::new(),
+            batch_spent: None,
+            change_policy: ChangePolicy::default(),
+            min_confirmations: None,
+            candidate_order: None,
+            fee_estimator: None,
+            fee_target_blocks: None,
+            anti_fee_sniping: false,
+            dust_change_to_fee: true,
+            single_keychain: false,
+            max_inputs: None,
+            change_to_recipient: None,
+            fee_rate_from_history: false,
+            randomize_change_position: false,
+            #[cfg(feature = "std")]
+            rng: None,
+            label: None,
+            message: None,
         }
     }
 
-    pub fn add_recipient(mut self, address: Address, amount: Amount) -> Self {
-        self.recipients.push((address, amount));
+    /// Add an output paying `amount` to `address`.
+    ///
+    /// Fails with [`TxBuilderError::InvalidRecipient`] if `address` isn't valid for the wallet's
+    /// network, e.g. a mainnet address pasted into a signet wallet.
+    pub fn add_recipient(
+        mut self,
+        address: Address<NetworkUnchecked>,
+        amount: Amount,
+    ) -> Result<Self, WalletError> {
+        if !address.is_valid_for_network(self.wallet.network()) {
+            return Err(TxBuilderError::InvalidRecipient.into());
+        }
+        let address = address.assume_checked();
+        self.recipients.push((address.script_pubkey(), amount));
+        Ok(self)
+    }
+
+    /// Add an output paying `amount` to a raw `script`, e.g. one without an address form.
+    ///
+    /// Unlike [`add_recipient`](Self::add_recipient) there is no address to validate against the
+    /// wallet's network, but the amount must still be above the script's dust threshold.
+    pub fn add_recipient_script(mut self, script: ScriptBuf, amount: Amount) -> Self {
+        self.recipients.push((script, amount));
+        self
+    }
+
+    /// Add several recipients at once.
+    ///
+    /// Like [`add_recipient`](Self::add_recipient) the addresses must be valid for the wallet's
+    /// network, else [`finish`](Self::finish) fails with [`TxBuilderError::InvalidRecipient`].
+    pub fn add_recipients(mut self, recipients: impl IntoIterator<Item = (Address, Amount)>) -> Self {
+        for (address, amount) in recipients {
+            if !self.is_valid_address(&address) {
+                self.recipient_network_mismatch = true;
+            }
+            self.recipients.push((address.script_pubkey(), amount));
+        }
         self
     }
 
+    /// Replace all recipients added so far with `recipients`, see
+    /// [`add_recipients`](Self::add_recipients).
+    pub fn set_recipients(mut self, recipients: Vec<(Address, Amount)>) -> Self {
+        self.recipients.clear();
+        self.recipient_network_mismatch = false;
+        self.add_recipients(recipients)
+    }
+
+    /// Whether `address` is valid for the wallet's network.
+    fn is_valid_address(&self, address: &Address) -> bool {
+        address
+            .as_unchecked()
+            .is_valid_for_network(self.wallet.network())
+    }
+
+    /// Add a recipient from a [BIP-21] `bitcoin:` URI.
+    ///
+    /// The URI must contain an address valid for the wallet's network and an `amount`. A `label`
+    /// or `message` is carried over to the [`TransactionDetails`]. Unknown parameters are ignored,
+    /// except `req-` parameters which BIP-21 requires to be understood.
+    ///
+    /// [BIP-21]: https://github.com/bitcoin/bips/blob/master/bip-0021.mediawiki
+    pub fn add_bip21(mut self, uri: &str) -> Result<Self, WalletError> {
+        let rest = uri
+            .get(..8)
+            .filter(|scheme| scheme.eq_ignore_ascii_case("bitcoin:"))
+            .map(|_| &uri[8..])
+            .ok_or(TxBuilderError::InvalidRecipient)?;
+        let (address, query) = match rest.split_once('?') {
+            Some((address, query)) => (address, query),
+            None => (rest, ""),
+        };
+        if address.is_empty() {
+            return Err(TxBuilderError::InvalidRecipient.into());
+        }
+        let address = address
+            .parse::<Address<NetworkUnchecked>>()
+            .map_err(|_| TxBuilderError::InvalidRecipient)?;
+
+        let mut amount = None;
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            match key {
+                "amount" => {
+                    amount = Some(
+                        Amount::from_str_in(value, Denomination::Bitcoin)
+                            .map_err(|_| TxBuilderError::InvalidRecipient)?,
+                    )
+                }
+                "label" => {
+                    self.label = Some(percent_decode(value).ok_or(TxBuilderError::InvalidRecipient)?)
+                }
+                "message" => {
+                    self.message =
+                        Some(percent_decode(value).ok_or(TxBuilderError::InvalidRecipient)?)
+                }
+                key if key.starts_with("req-") => {
+                    return Err(TxBuilderError::InvalidRecipient.into())
+                }
+                _ => {}
+            }
+        }
+        let amount = amount.ok_or(TxBuilderError::InvalidRecipient)?;
+
+        self.add_recipient(address, amount)
+    }
+
     pub fn fee_rate(mut self, fee_rate: FeeRate) -> Self {
         self.fee_rate = Some(fee_rate);
         self
     }
 
+    /// Use `estimator` to resolve [`fee_target_blocks`](Self::fee_target_blocks) to a fee rate.
+    pub fn fee_estimator(mut self, estimator: &'a dyn FeeEstimator) -> Self {
+        self.fee_estimator = Some(estimator);
+        self
+    }
+
+    /// Target confirmation within `target_blocks` blocks.
+    ///
+    /// The fee rate is resolved with the configured [`FeeEstimator`] when the transaction is
+    /// finished. An explicit [`fee_rate`](Self::fee_rate) takes precedence.
+    pub fn fee_target_blocks(mut self, target_blocks: u16) -> Self {
+        self.fee_target_blocks = Some(target_blocks);
+        self
+    }
+
+    /// Without an explicit fee rate or confirmation target, use the highest fee rate among the
+    /// wallet's most recent confirmed transactions instead of 1 sat/vB.
+    ///
+    /// The fee rate is clamped to between 1 and 100 sat/vB, and falls back to 1 sat/vB if none
+    /// of those transactions has a known fee. This gives a more realistic default offline.
+    pub fn fee_rate_from_history(mut self) -> Self {
+        self.fee_rate_from_history = true;
+        self
+    }
+
     pub fn prefer_keychain(mut self, keychain: K) -> Self {
         self.preferred_keychain = Some(keychain);
         self
     }
 
+    /// Set the locktime to the current tip height, so that the transaction can't be mined in
+    /// a block that reorgs the tip.
+    ///
+    /// This discourages miners from reorging to take fees ("fee sniping"), and makes the
+    /// transaction look like those of other wallets doing the same, so it is recommended. With
+    /// the `std` feature the locktime is occasionally set a few blocks back, as a transaction that
+    /// was delayed would be, drawn from the RNG set with [`rng`](Self::rng) or the thread RNG.
+    /// Off by default so that builds are reproducible.
+    pub fn enable_anti_fee_sniping(mut self) -> Self {
+        self.anti_fee_sniping = true;
+        self
+    }
+
+    /// Whether change below the dust threshold may be added to the fee instead of creating an
+    /// output for it. Defaults to `true`.
+    ///
+    /// The absorbed change is included in [`TransactionDetails::fee`]. When disabled, building a
+    /// transaction that would leave dust change fails with [`TxBuilderError::DustOutput`].
+    pub fn allow_dust_change_to_fee(mut self, allow: bool) -> Self {
+        self.dust_change_to_fee = allow;
+        self
+    }
+
+    /// Add the change to the amount of the recipient at `index`, counting recipients in the
+    /// order they were added, instead of creating a change output.
+    ///
+    /// This saves an output, e.g. when consolidating to an address of our own. Building fails
+    /// with [`TxBuilderError::InvalidRecipient`] if there is no recipient at `index`.
+    pub fn add_change_to_recipient(mut self, index: usize) -> Self {
+        self.change_to_recipient = Some(index);
+        self
+    }
+
+    /// Whether to put the change output at a random position among the outputs, rather than
+    /// last where it's easy to tell apart. Defaults to `false` so that builds are reproducible.
+    ///
+    /// The position is drawn from the RNG set with [`rng`](Self::rng), or the thread RNG.
+    /// Without the `std` feature change is always last.
+    pub fn randomize_change_position(mut self, randomize: bool) -> Self {
+        self.randomize_change_position = randomize;
+        self
+    }
+
+    /// Use `rng` to pick the change position and the anti-fee-sniping locktime, e.g. a seeded RNG
+    /// to get the same transaction on every build. See
+    /// [`randomize_change_position`](Self::randomize_change_position) and
+    /// [`enable_anti_fee_sniping`](Self::enable_anti_fee_sniping).
+    #[cfg(feature = "std")]
+    pub fn rng(mut self, rng: &'a mut dyn bitcoin::secp256k1::rand::RngCore) -> Self {
+        self.rng = Some(rng);
+        self
+    }
+
     pub fn drain_wallet(mut self) -> Self {
         self.drain_wallet = true;
         self
     }
 
+    /// Drain the wallet to several addresses, splitting what is left after fees by weight.
+    ///
+    /// The weights must be positive and sum to 1.0, and the addresses valid for the wallet's
+    /// network. Any rounding remainder goes to the first destination, and every output must end
+    /// up above its dust threshold.
+    pub fn drain_to_many(mut self, destinations: Vec<(Address, f64)>) -> Self {
+        self.drain_wallet = true;
+        self.drain_to = destinations;
+        self
+    }
+
+    /// Spend `outpoint`, a UTXO of the wallet, whether or not coin selection would pick it.
+    ///
+    /// Coin selection adds more inputs if the manually added ones don't cover the recipients and
+    /// fee. [`finish`](Self::finish) fails with [`TxBuilderError::UnknownUtxo`] if `outpoint`
+    /// isn't a spendable output of the wallet.
     pub fn add_utxo(mut self, outpoint: OutPoint) -> Self {
         self.utxos.push(outpoint);
         self
     }
 
+    /// Never spend `outpoint`, e.g. because another transaction that hasn't been broadcast yet
+    /// already spends it.
+    pub fn add_unspendable(mut self, outpoint: OutPoint) -> Self {
+        self.unspendable.insert(outpoint);
+        self
+    }
+
+    /// Choose how the change address is picked, see [`ChangePolicy`].
+    pub fn change_policy(mut self, change_policy: ChangePolicy) -> Self {
+        self.change_policy = change_policy;
+        self
+    }
+
+    /// Only spend UTXOs from a single keychain, so that the transaction doesn't link otherwise
+    /// separate keychains such as different accounts.
+    ///
+    /// The keychain that can fund the transaction with the fewest inputs is picked. If none can
+    /// on its own, [`finish`](Self::finish) fails with [`TxBuilderError::InsufficientFunds`]
+    /// reporting the most a single keychain has available, even if combining keychains would be
+    /// enough.
+    ///
+    /// UTXOs added with [`add_utxo`](Self::add_utxo) pick the keychain instead, and building
+    /// fails with [`TxBuilderError::MixedKeychains`] if they belong to more than one.
+    pub fn restrict_to_single_keychain(mut self) -> Self {
+        self.single_keychain = true;
+        self
+    }
+
+    /// Only spend confirmed UTXOs, excluding anything still in the mempool.
+    pub fn only_confirmed(self) -> Self {
+        self.min_confirmations(1)
+    }
+
+    /// Spend at most `max_inputs` inputs, e.g. to keep transactions cheap to sign on hardware.
+    ///
+    /// Fails with [`TxBuilderError::InsufficientFunds`] if the target can't be reached within the
+    /// cap. Doesn't apply when [draining the wallet](Self::drain_wallet).
+    pub fn max_inputs(mut self, max_inputs: usize) -> Self {
+        self.max_inputs = Some(max_inputs);
+        self
+    }
+
+    /// Only spend UTXOs with at least `confirmations` confirmations at the current tip.
+    pub fn min_confirmations(mut self, confirmations: u32) -> Self {
+        self.min_confirmations = Some(confirmations);
+        self
+    }
+
+    /// Order candidate UTXOs with `compare` before coin selection accumulates them.
+    ///
+    /// Coins are selected from the front of the sorted list. Defaults to largest-first.
+    pub fn sort_candidates_by(
+        mut self,
+        compare: Box<dyn Fn(&LocalUtxo<K>, &LocalUtxo<K>) -> Ordering + 'a>,
+    ) -> Self {
+        self.candidate_order = Some(compare);
+        self
+    }
+
 
     fn get_available_utxos(&self) -> Result<Vec<LocalUtxo<K>>, WalletError> {
         let chain = self.wallet.local_chain();
         let tx_graph = self.wallet.tx_graph();
         let tip = chain.tip().block_id();
-        let params = CanonicalizationParams::default();
+        let params = self.wallet.canonicalization_params().clone();
 
         let mut utxos = Vec::new();
+        let mut immature_coinbase = false;
 
         for ((keychain, index), outpoint) in tx_graph.index.outpoints() {
+            if self.unspendable.contains(outpoint)
+                || self.batch_spent.as_ref().map_or(false, |spent| spent.contains(outpoint))
+            {
+                continue;
+            }
             if let Some(preferred) = &self.preferred_keychain {
                 if keychain != preferred {
                     continue;
@@ -71,80 +393,466 @@ where
 
             if let Some(tx_node) = tx_graph.graph().get_tx_node(outpoint.txid) {
                 if let Some(txout) = tx_node.tx.output.get(outpoint.vout as usize) {
-                    let is_unspent = tx_graph.graph()
+                    let unspent = tx_graph.graph()
                         .filter_chain_unspents(chain, tip, params.clone(), [((), *outpoint)].iter().cloned())
-                        .next()
-                        .is_some();
+                        .next();
+
+                    if let Some((_, full_txout)) = unspent {
+                        // Coinbase outputs can't be spent until they have matured
+                        if !full_txout.is_mature(tip.height) {
+                            immature_coinbase = true;
+                            continue;
+                        }
+
+                        if let Some(min_conf) = self.min_confirmations {
+                            let confirmations = full_txout
+                                .chain_position
+                                .confirmation_height_upper_bound()
+                                .map_or(0, |height| tip.height.saturating_sub(height) + 1);
+                            if confirmations < min_conf {
+                                continue;
+                            }
+                        }
 
-                    if is_unspent {
+                        let address =
+                            Address::from_script(&txout.script_pubkey, self.wallet.network()).ok();
                         utxos.push(LocalUtxo {
                             outpoint: *outpoint,
                             txout: txout.clone(),
                             keychain: keychain.clone(),
                             derivation_index: *index,
+                            address,
+                            chain_position: full_txout.chain_position,
                         });
                     }
                 }
             }
         }
 
+        if utxos.is_empty() && immature_coinbase {
+            return Err(TxBuilderError::ImmatureCoinbase.into());
+        }
+
         Ok(utxos)
     }
 
-    fn select_coins(&self, mut utxos: Vec<LocalUtxo<K>>, fee_rate: FeeRate) -> Result<Vec<LocalUtxo<K>>, WalletError> {
-        if utxos.is_empty() {
+    /// Sum of the recipient amounts, rejecting zero or dust amounts and totals that overflow.
+    ///
+    /// When draining the amounts are ignored, so they are not checked.
+    fn recipients_total(&self) -> Result<Amount, WalletError> {
+        let mut total = Amount::ZERO;
+        for (script, amount) in &self.recipients {
+            if !self.drain_wallet {
+                if *amount == Amount::ZERO {
+                    return Err(TxBuilderError::InvalidRecipient.into());
+                }
+                if *amount < script.minimal_non_dust() {
+                    return Err(TxBuilderError::DustOutput.into());
+                }
+            }
+            total = total
+                .checked_add(*amount)
+                .ok_or(TxBuilderError::InvalidRecipient)?;
+        }
+        Ok(total)
+    }
+
+    fn resolve_fee_rate(&self) -> Result<FeeRate, WalletError> {
+        if let Some(fee_rate) = self.fee_rate {
+            return Ok(fee_rate);
+        }
+
+        match self.fee_target_blocks {
+            Some(target_blocks) => self
+                .fee_estimator
+                .and_then(|estimator| estimator.estimate_fee_rate(target_blocks))
+                .ok_or_else(|| TxBuilderError::FeeEstimation.into()),
+            None if self.fee_rate_from_history => Ok(self.historical_fee_rate()),
+            None => Ok(FeeRate::from_sat_per_vb_unchecked(1)),
+        }
+    }
+
+    /// Highest fee rate among the wallet's most recent confirmed transactions, see
+    /// [`fee_rate_from_history`](Self::fee_rate_from_history).
+    fn historical_fee_rate(&self) -> FeeRate {
+        const RECENT_TXS: usize = 10;
+        let min = FeeRate::from_sat_per_vb_unchecked(1);
+        let max = FeeRate::from_sat_per_vb_unchecked(100);
+
+        let mut confirmed: Vec<(u32, bitcoin::Txid)> = self
+            .wallet
+            .transactions()
+            .filter_map(|canonical_tx| match canonical_tx.chain_position {
+                ChainPosition::Confirmed { anchor, .. } => {
+                    Some((anchor.block_id.height, canonical_tx.tx_node.txid))
+                }
+                ChainPosition::Unconfirmed { .. } => None,
+            })
+            .collect();
+        confirmed.sort_unstable_by(|a, b| b.cmp(a));
+
+        confirmed
+            .into_iter()
+            .take(RECENT_TXS)
+            .filter_map(|(_, txid)| self.wallet.tx_feerate(txid))
+            .max()
+            .map_or(min, |fee_rate| fee_rate.clamp(min, max))
+    }
+
+    fn select_coins(&self, utxos: Vec<LocalUtxo<K>>, fee_rate: FeeRate) -> Result<Vec<LocalUtxo<K>>, WalletError> {
+        // Outputs spent by earlier builds of a batch are still funds, just not available to this
+        // transaction
+        let batch_spent = self.batch_spent.as_ref().map_or(false, |spent| !spent.is_empty());
+        if utxos.is_empty() && !batch_spent {
             return Err(TxBuilderError::NoUtxos.into());
         }
 
-        // Sort by value (largest first)
-        utxos.sort_by(|a, b| b.txout.value.cmp(&a.txout.value));
+        // UTXOs added with `add_utxo` are always spent, whatever they cost
+        let mut utxos = utxos;
+        let mut manual: Vec<LocalUtxo<K>> = Vec::with_capacity(self.utxos.len());
+        for outpoint in &self.utxos {
+            if manual.iter().any(|utxo| utxo.outpoint == *outpoint) {
+                continue;
+            }
+            let position = utxos
+                .iter()
+                .position(|utxo| utxo.outpoint == *outpoint)
+                .ok_or(TxBuilderError::UnknownUtxo(*outpoint))?;
+            manual.push(utxos.swap_remove(position));
+        }
+
+        // Skip UTXOs that cost more to spend than they are worth
+        let mut candidates: Vec<(LocalUtxo<K>, Amount)> = utxos
+            .into_iter()
+            .filter_map(|utxo| {
+                let effective_value = self.effective_value(&utxo, fee_rate)?;
+                Some((utxo, effective_value))
+            })
+            .collect();
+
+        // Manually added UTXOs pick the keychain to spend from
+        if self.single_keychain {
+            if let Some(keychain) = manual.first().map(|utxo| utxo.keychain.clone()) {
+                if manual.iter().any(|utxo| utxo.keychain != keychain) {
+                    return Err(TxBuilderError::MixedKeychains.into());
+                }
+                candidates.retain(|(utxo, _)| utxo.keychain == keychain);
+            }
+        }
+
+        match &self.candidate_order {
+            Some(compare) => candidates.sort_by(|(a, _), (b, _)| compare(a, b)),
+            // Sort by effective value (largest first)
+            None => candidates.sort_by(|(_, a), (_, b)| b.cmp(a)),
+        }
 
         if self.drain_wallet {
-            return Ok(utxos);
+            if candidates.is_empty() && manual.is_empty() {
+                return Err(TxBuilderError::NoUtxos.into());
+            }
+            manual.extend(candidates.into_iter().map(|(utxo, _)| utxo));
+            return Ok(manual);
         }
 
-        let target: Amount = self.recipients.iter().map(|(_, amount)| *amount).sum();
-        let mut selected = Vec::new();
-        let mut selected_value = Amount::ZERO;
+        let target = self.recipients_total()?;
+        // Fee for everything but the inputs, which is already deducted from the effective values,
+        // assuming a segwit marker if any input might need one
+        let utxos = manual.iter().chain(candidates.iter().map(|(utxo, _)| utxo));
+        let base_weight = self.base_weight() + self.segwit_marker_weight(utxos);
+        let base_fee = fee_rate.fee_wu(base_weight).unwrap_or(Amount::ZERO);
+        // The manually added UTXOs pay for their own inputs, and the rest is selected to cover
+        // whatever they leave
+        let manual_weight: Weight = manual.iter().map(|utxo| self.input_weight(utxo)).sum();
+        let manual_fee = fee_rate.fee_wu(manual_weight).unwrap_or(Amount::ZERO);
+        let manual_value: Amount = manual.iter().map(|utxo| utxo.txout.value).sum();
+        let required = target + base_fee + manual_fee;
+        let still_required = required.checked_sub(manual_value).unwrap_or(Amount::ZERO);
+        let max_inputs = self.max_inputs.map(|max| max.saturating_sub(manual.len()));
+        let insufficient_funds = |available: Amount| -> WalletError {
+            TxBuilderError::InsufficientFunds {
+                required: required.to_sat(),
+                available: (manual_value + available).to_sat(),
+            }
+            .into()
+        };
 
-        for utxo in utxos {
-            selected.push(utxo);
-            selected_value += selected.last().unwrap().txout.value;
+        if !self.single_keychain {
+            let selected = Self::accumulate(candidates, still_required, max_inputs)
+                .map_err(insufficient_funds)?;
+            manual.extend(selected);
+            return Ok(manual);
+        }
+
+        // Select from each keychain on its own and keep the selection with the fewest inputs.
+        // If no keychain can fund the target alone, report the most a single keychain can provide.
+        let mut by_keychain: BTreeMap<K, Vec<(LocalUtxo<K>, Amount)>> = BTreeMap::new();
+        for (utxo, effective_value) in candidates {
+            by_keychain
+                .entry(utxo.keychain.clone())
+                .or_default()
+                .push((utxo, effective_value));
+        }
+        let mut best: Option<Vec<LocalUtxo<K>>> = None;
+        let mut best_available = Amount::ZERO;
+        for (_, keychain_candidates) in by_keychain {
+            match Self::accumulate(keychain_candidates, still_required, max_inputs) {
+                Ok(selected) => {
+                    if best.as_ref().map_or(true, |best| selected.len() < best.len()) {
+                        best = Some(selected);
+                    }
+                }
+                Err(available) => best_available = best_available.max(available),
+            }
+        }
+        let selected = best.ok_or_else(|| insufficient_funds(best_available))?;
+        manual.extend(selected);
+        Ok(manual)
+    }
 
-            let estimated_fee = fee_rate.fee_vb(self.estimate_tx_size(selected.len(), self.recipients.len())).unwrap_or(Amount::ZERO);
+    /// Take `candidates` in order until their effective value covers `required`, taking at most
+    /// `max_inputs` of them.
+    ///
+    /// Returns the total effective value of the candidates taken if they fall short.
+    fn accumulate(
+        candidates: Vec<(LocalUtxo<K>, Amount)>,
+        required: Amount,
+        max_inputs: Option<usize>,
+    ) -> Result<Vec<LocalUtxo<K>>, Amount> {
+        let mut selected = Vec::new();
+        let mut selected_value = Amount::ZERO;
 
-            if selected_value >= target + estimated_fee {
+        for (utxo, effective_value) in candidates {
+            if selected_value >= required || Some(selected.len()) == max_inputs {
                 break;
             }
+            selected.push(utxo);
+            selected_value += effective_value;
         }
 
-        let final_fee = fee_rate.fee_vb(self.estimate_tx_size(selected.len(), self.recipients.len())).unwrap_or(Amount::ZERO);
-        if selected_value < target + final_fee {
-            return Err(TxBuilderError::InsufficientFunds {
-                required: (target + final_fee).to_sat(),
-                available: selected_value.to_sat()
-            }.into());
+        if selected_value < required {
+            return Err(selected_value);
         }
 
         Ok(selected)
     }
 
-    fn estimate_tx_size(&self, inputs: usize, outputs: usize) -> u64 {
-        // Simplified transaction size estimation
-        let base_size = 10u64; // version, locktime, etc.
-        let input_size = inputs as u64 * 148; // approximate P2WPKH input size
-        let output_size = outputs as u64 * 34; // approximate output size
-        base_size + input_size + output_size
+    fn input_weight(&self, utxo: &LocalUtxo<K>) -> Weight {
+        // Fall back to the size of a P2WPKH input if the descriptor can't tell us
+        self.wallet
+            .input_weight(&utxo.keychain)
+            .unwrap_or(Weight::from_vb_unchecked(148))
+    }
+
+    /// Value of `utxo` minus the fee to spend it, or `None` if that is not positive.
+    fn effective_value(&self, utxo: &LocalUtxo<K>, fee_rate: FeeRate) -> Option<Amount> {
+        let input_fee = fee_rate.fee_wu(self.input_weight(utxo))?;
+        utxo.txout
+            .value
+            .checked_sub(input_fee)
+            .filter(|value| *value > Amount::ZERO)
+    }
+
+    /// Fee of a transaction spending `selected`, without a change output.
+    fn estimate_fee(&self, selected: &[LocalUtxo<K>], fee_rate: FeeRate) -> Amount {
+        let input_weight: Weight = selected.iter().map(|utxo| self.input_weight(utxo)).sum();
+        let weight = self.base_weight() + self.segwit_marker_weight(selected) + input_weight;
+        fee_rate.fee_wu(weight).unwrap_or(Amount::ZERO)
+    }
+
+    /// Scripts the transaction pays to, not counting change.
+    fn output_scripts(&self) -> impl Iterator<Item = ScriptBuf> + '_ {
+        // Draining to many ignores the recipients
+        let recipients = if self.drain_to.is_empty() { &self.recipients[..] } else { &[] };
+        recipients
+            .iter()
+            .map(|(script, _)| script.clone())
+            .chain(self.drain_to.iter().map(|(address, _)| address.script_pubkey()))
+    }
+
+    fn check_drain_weights(&self) -> Result<(), WalletError> {
+        const TOLERANCE: f64 = 1e-9;
+        if self.drain_to.is_empty() {
+            return Ok(());
+        }
+        if self
+            .drain_to
+            .iter()
+            .any(|(_, weight)| !weight.is_finite() || *weight <= 0.0)
+        {
+            return Err(TxBuilderError::InvalidRecipient.into());
+        }
+        let total: f64 = self.drain_to.iter().map(|(_, weight)| weight).sum();
+        if !(1.0 - TOLERANCE..=1.0 + TOLERANCE).contains(&total) {
+            return Err(TxBuilderError::InvalidRecipient.into());
+        }
+        Ok(())
+    }
+
+    /// Split `total` between the [`drain_to_many`](Self::drain_to_many) destinations.
+    fn split_drain(&self, total: Amount) -> Result<Vec<TxOut>, WalletError> {
+        let mut outputs: Vec<TxOut> = self
+            .drain_to
+            .iter()
+            .map(|(address, weight)| TxOut {
+                // Truncating rounds down, the remainder is handed out below
+                value: Amount::from_sat((total.to_sat() as f64 * weight) as u64),
+                script_pubkey: address.script_pubkey(),
+            })
+            .collect();
+
+        let distributed: Amount = outputs.iter().map(|txout| txout.value).sum();
+        let remainder = total
+            .checked_sub(distributed)
+            .ok_or(TxBuilderError::InvalidRecipient)?;
+        if let Some(first) = outputs.first_mut() {
+            first.value += remainder;
+        }
+
+        if outputs
+            .iter()
+            .any(|txout| txout.value < txout.script_pubkey.minimal_non_dust())
+        {
+            return Err(TxBuilderError::DustOutput.into());
+        }
+        Ok(outputs)
+    }
+
+    /// Weight of the transaction without its inputs, which are weighed separately, or its change
+    /// output, which is only added if it is worth its own fee.
+    ///
+    /// The segwit marker and flag are left out too, see
+    /// [`segwit_marker_weight`](Self::segwit_marker_weight).
+    fn base_weight(&self) -> Weight {
+        // version, locktime, input and output counts
+        let header = Weight::from_non_witness_data_size(4 + 4 + 1 + 1);
+        let outputs: Weight = self
+            .output_scripts()
+            .map(|script_pubkey| {
+                TxOut {
+                    value: Amount::ZERO,
+                    script_pubkey,
+                }
+                .weight()
+            })
+            .sum();
+        header + outputs
+    }
+
+    /// Weight of the segwit marker and flag, which a transaction only has if one of its inputs,
+    /// spending `utxos`, has a witness.
+    fn segwit_marker_weight<'u>(&self, utxos: impl IntoIterator<Item = &'u LocalUtxo<K>>) -> Weight
+    where
+        K: 'u,
+    {
+        let has_witness = utxos.into_iter().any(|utxo| {
+            self.wallet
+                .get_keychain_descriptor(&utxo.keychain)
+                .map_or(true, |descriptor| descriptor.desc_type().segwit_version().is_some())
+        });
+        if has_witness {
+            Weight::from_wu(2)
+        } else {
+            Weight::ZERO
+        }
+    }
+
+    fn lock_time(&mut self) -> bitcoin::absolute::LockTime {
+        if !self.anti_fee_sniping {
+            return bitcoin::absolute::LockTime::ZERO;
+        }
+
+        #[allow(unused_mut)]
+        let mut height = self.wallet.local_chain().tip().height();
+        // Like Bitcoin Core, one time in ten pick a height up to 100 blocks back
+        #[cfg(feature = "std")]
+        {
+            use bitcoin::secp256k1::rand::{thread_rng, Rng, RngCore};
+            let mut thread_rng = thread_rng();
+            let rng: &mut dyn RngCore = match self.rng.as_mut() {
+                Some(rng) => *rng,
+                None => &mut thread_rng,
+            };
+            if rng.gen_range(0..10) == 0 {
+                height = height.saturating_sub(rng.gen_range(0..100));
+            }
+        }
+
+        // The inputs signal RBF, which also enables the locktime
+        bitcoin::absolute::LockTime::from_height(height)
+            .unwrap_or(bitcoin::absolute::LockTime::ZERO)
+    }
+
+    /// Move the change output at `change_vout`, the last output, to a random position if
+    /// enabled. Returns its new position.
+    fn shuffle_change(&mut self, tx: &mut Transaction, change_vout: usize) -> usize {
+        if !self.randomize_change_position {
+            return change_vout;
+        }
+        #[cfg(feature = "std")]
+        {
+            use bitcoin::secp256k1::rand::{thread_rng, Rng};
+            let position = match self.rng.as_mut() {
+                Some(rng) => rng.gen_range(0..=change_vout),
+                None => thread_rng().gen_range(0..=change_vout),
+            };
+            let change = tx.output.remove(change_vout);
+            tx.output.insert(position, change);
+            position
+        }
+        #[cfg(not(feature = "std"))]
+        {
+            let _ = tx;
+            change_vout
+        }
+    }
+
+    /// Address to send the change of spending `selected_utxos` to, without revealing it.
+    ///
+    /// Prefers the change keychain split from the same multipath descriptor as the spent inputs,
+    /// falling back to the keychain of the first input.
+    fn change_address(&self, selected_utxos: &[LocalUtxo<K>]) -> Option<KeychainIndexed<K, Address>> {
+        let utxo = selected_utxos.first()?;
+        let keychain = self
+            .wallet
+            .keyring()
+            .change_keychain_of(&utxo.keychain)
+            .unwrap_or_else(|| utxo.keychain.clone());
+        match self.change_policy {
+            ChangePolicy::NewEachTime => self.wallet.peek_next_address(keychain),
+            ChangePolicy::ReuseLastUnused => self
+                .wallet
+                .unused_addresses(keychain.clone())
+                .next()
+                .or_else(|| self.wallet.peek_next_address(keychain)),
+        }
     }
 
     fn create_psbt(&mut self, selected_utxos: Vec<LocalUtxo<K>>, fee_rate: FeeRate) -> Result<(Psbt, TransactionDetails), WalletError> {
         let selected_value: Amount = selected_utxos.iter().map(|u| u.txout.value).sum();
-        let target_value: Amount = self.recipients.iter().map(|(_, amount)| *amount).sum();
-        let estimated_fee = fee_rate.fee_vb(self.estimate_tx_size(selected_utxos.len(), self.recipients.len())).unwrap_or(Amount::ZERO);
+        let target_value = self.recipients_total()?;
+        let estimated_fee = self.estimate_fee(&selected_utxos, fee_rate);
+
+        // Coin selection makes sure the inputs cover the outputs and fee, but check anyway rather
+        // than panic on underflow
+        let insufficient_funds = |required: Amount| TxBuilderError::InsufficientFunds {
+            required: required.to_sat(),
+            available: selected_value.to_sat(),
+        };
+        let required = if self.drain_wallet {
+            estimated_fee
+        } else {
+            target_value
+                .checked_add(estimated_fee)
+                .ok_or(TxBuilderError::InvalidRecipient)?
+        };
+        // What is left to drain, or the change
+        let remaining = selected_value
+            .checked_sub(required)
+            .ok_or_else(|| insufficient_funds(required))?;
 
         let mut tx = Transaction {
             version: bitcoin::transaction::Version::TWO,
-            lock_time: bitcoin::absolute::LockTime::ZERO,
+            lock_time: self.lock_time(),
             input: Vec::new(),
             output: Vec::new(),
         };
@@ -160,70 +868,251 @@ where
         }
 
         // Add outputs
-        if self.drain_wallet {
-            if let Some((address, _)) = self.recipients.first() {
+        let mut change_index = None;
+        let mut change_vout = None;
+        if !self.drain_to.is_empty() {
+            tx.output = self.split_drain(remaining)?;
+        } else if self.drain_wallet {
+            if let Some((script, _)) = self.recipients.first() {
                 tx.output.push(TxOut {
-                    value: selected_value - estimated_fee,
-                    script_pubkey: address.script_pubkey(),
+                    value: remaining,
+                    script_pubkey: script.clone(),
                 });
             }
         } else {
-            for (address, amount) in &self.recipients {
+            for (script, amount) in &self.recipients {
                 tx.output.push(TxOut {
                     value: *amount,
-                    script_pubkey: address.script_pubkey(),
+                    script_pubkey: script.clone(),
                 });
             }
 
-            // Add change if needed
-            let change = selected_value - target_value - estimated_fee;
-            if change > Amount::from_sat(546) { // dust threshold
-                if let Some(keychain) = selected_utxos.first().map(|u| u.keychain.clone()) {
-                    if let Some(((_, _), change_addr)) = self.wallet.reveal_next_address(keychain) {
+            // Add change if needed. The change address is only peeked here and revealed once
+            // the PSBT is built, so a failed build doesn't use it up.
+            if let Some(index) = self.change_to_recipient {
+                let recipient = tx
+                    .output
+                    .get_mut(index)
+                    .ok_or(TxBuilderError::InvalidRecipient)?;
+                recipient.value += remaining;
+            } else if remaining > Amount::ZERO {
+                if let Some((keychain_index, change_addr)) = self.change_address(&selected_utxos) {
+                    // The change output pays for its own weight out of what is left over
+                    let script_pubkey = change_addr.script_pubkey();
+                    let change_weight = TxOut {
+                        value: Amount::ZERO,
+                        script_pubkey: script_pubkey.clone(),
+                    }
+                    .weight();
+                    let change = fee_rate
+                        .fee_wu(change_weight)
+                        .and_then(|change_fee| remaining.checked_sub(change_fee))
+                        .unwrap_or(Amount::ZERO);
+                    if change >= script_pubkey.minimal_non_dust() {
                         tx.output.push(TxOut {
                             value: change,
-                            script_pubkey: change_addr.script_pubkey(),
+                            script_pubkey,
                         });
+                        change_index = Some(keychain_index);
+                        let last = tx.output.len() - 1;
+                        change_vout = Some(self.shuffle_change(&mut tx, last));
+                    } else if !self.dust_change_to_fee {
+                        return Err(TxBuilderError::DustOutput.into());
                     }
                 }
             }
         }
 
-        let psbt = Psbt::from_unsigned_tx(tx)
+        // Any change below the dust threshold is absorbed into the fee, and the fee estimate
+        // leaves out the change output, so report what the transaction actually pays.
+        let output_value: Amount = tx.output.iter().map(|txout| txout.value).sum();
+        let fee = selected_value
+            .checked_sub(output_value)
+            .ok_or_else(|| insufficient_funds(output_value))?;
+
+        // The unsigned transaction has no witnesses yet, so add the segwit marker and flag if any
+        // input has a witness, and the expected satisfaction of each input
+        let satisfaction_weight: Weight = selected_utxos
+            .iter()
+            .map(|utxo| self.input_weight(utxo) - TxIn::default().legacy_weight())
+            .sum();
+        let weight = tx.weight() + self.segwit_marker_weight(&selected_utxos) + satisfaction_weight;
+
+        let mut psbt = Psbt::from_unsigned_tx(tx)
             .map_err(|_| TxBuilderError::PsbtCreation)?;
+        for (psbt_input, utxo) in psbt.inputs.iter_mut().zip(&selected_utxos) {
+            self.populate_psbt_input(psbt_input, utxo)?;
+        }
+
+        if let Some((keychain, index)) = change_index {
+            if let WalletRef::Mut(wallet) = &mut self.wallet {
+                wallet.reveal_addresses_to(keychain, index)?;
+            }
+        }
 
         let details = TransactionDetails {
             txid: psbt.unsigned_tx.compute_txid(),
-            sent: if self.drain_wallet { selected_value - estimated_fee } else { target_value },
+            sent: if self.drain_wallet { remaining } else { target_value },
             received: Amount::ZERO,
-            fee: Some(estimated_fee),
+            fee: Some(fee),
+            weight,
+            change_vout: change_vout.map(|vout| vout as u32),
+            label: self.label.clone(),
+            message: self.message.clone(),
         };
 
         Ok((psbt, details))
     }
 
+    /// Fill in what a signer needs to sign `psbt_input`, which spends `utxo`.
+    fn populate_psbt_input(&self, psbt_input: &mut psbt::Input, utxo: &LocalUtxo<K>) -> Result<(), WalletError> {
+        let descriptor = self.wallet
+            .get_keychain_descriptor(&utxo.keychain)
+            .ok_or(TxBuilderError::PsbtCreation)?
+            .at_derivation_index(utxo.derivation_index)
+            .map_err(|_| TxBuilderError::PsbtCreation)?;
+
+        // Sets the key origins and scripts
+        psbt_input
+            .update_with_descriptor_unchecked(&descriptor)
+            .map_err(|_| TxBuilderError::PsbtCreation)?;
+
+        let segwit_version = descriptor.desc_type().segwit_version();
+        if segwit_version.is_some() {
+            psbt_input.witness_utxo = Some(utxo.txout.clone());
+        }
+        if segwit_version != Some(WitnessVersion::V1) {
+            // Pre-taproot signers need the previous transaction to verify the amount being spent
+            psbt_input.non_witness_utxo = self.wallet
+                .tx_graph()
+                .graph()
+                .get_tx(utxo.outpoint.txid)
+                .map(|tx| tx.as_ref().clone());
+        }
+
+        Ok(())
+    }
+
     pub fn finish(mut self) -> Result<(Psbt, TransactionDetails), WalletError> {
-        if self.recipients.is_empty() && !self.drain_wallet {
+        // Draining needs somewhere to send the funds, else they would all go to fees
+        if self.recipients.is_empty() && self.drain_to.is_empty() {
             return Err(TxBuilderError::NoRecipients.into());
         }
 
+        if self.recipient_network_mismatch
+            || self
+                .drain_to
+                .iter()
+                .any(|(address, _)| !self.is_valid_address(address))
+        {
+            return Err(TxBuilderError::InvalidRecipient.into());
+        }
+        self.check_drain_weights()?;
+        self.recipients_total()?;
+        if let Some(index) = self.change_to_recipient {
+            if index >= self.recipients.len() {
+                return Err(TxBuilderError::InvalidRecipient.into());
+            }
+        }
+
         let available_utxos = self.get_available_utxos()?;
-        let fee_rate = self.fee_rate.unwrap_or(FeeRate::from_sat_per_vb_unchecked(1));
+        let fee_rate = self.resolve_fee_rate()?;
 
         // Simple coin selection
         let selected_utxos = self.select_coins(available_utxos, fee_rate)?;
         let (psbt, details) = self.create_psbt(selected_utxos, fee_rate)?;
 
+        if let Some(spent) = &mut self.batch_spent {
+            spent.extend(psbt.unsigned_tx.input.iter().map(|txin| txin.previous_output));
+        }
+
         Ok((psbt, details))
     }
 }
 
+/// A session building several transactions that must not spend the same outputs, e.g. payments
+/// that are broadcast together.
+///
+/// Every builder from [`build_tx`](Self::build_tx) skips the outputs spent by the transactions
+/// built before it. Change addresses are revealed as each transaction is built, so they all end
+/// up in the wallet's staged changes and are persisted together.
+pub struct TxBatch<'a, K: Ord> {
+    wallet: &'a mut Wallet<K>,
+    spent: BTreeSet<OutPoint>,
+}
+
+impl<'a, K> TxBatch<'a, K>
+where
+    K: core::fmt::Debug + Clone + Ord,
+{
+    pub(crate) fn new(wallet: &'a mut Wallet<K>) -> Self {
+        Self {
+            wallet,
+            spent: BTreeSet::new(),
+        }
+    }
+
+    /// Start building the next transaction of the batch.
+    ///
+    /// Fails with [`TxBuilderError::InsufficientFunds`] if what the earlier builds left isn't
+    /// enough.
+    pub fn build_tx(&mut self) -> TxBuilder<'_, K> {
+        let mut builder = TxBuilder::new(self.wallet);
+        builder.batch_spent = Some(&mut self.spent);
+        builder
+    }
+
+    /// Outputs spent by the transactions built so far.
+    pub fn spent(&self) -> &BTreeSet<OutPoint> {
+        &self.spent
+    }
+}
+
+/// The wallet a [`TxBuilder`] builds from, borrowed mutably to reveal the change address, or
+/// shared when only estimating.
+enum WalletRef<'a, K: Ord> {
+    Mut(&'a mut Wallet<K>),
+    Shared(&'a Wallet<K>),
+}
+
+impl<'a, K: Ord> Deref for WalletRef<'a, K> {
+    type Target = Wallet<K>;
+
+    fn deref(&self) -> &Wallet<K> {
+        match self {
+            WalletRef::Mut(wallet) => wallet,
+            WalletRef::Shared(wallet) => wallet,
+        }
+    }
+}
+
+/// How [`TxBuilder`] picks the address that receives change.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChangePolicy {
+    /// Reveal a fresh change address for every transaction built.
+    ///
+    /// Every build advances the revealed index, even if the transaction is never broadcast, so
+    /// building and discarding many transactions leaves a long run of unused addresses.
+    #[default]
+    NewEachTime,
+    /// Use the lowest revealed change address that has not been used yet.
+    ///
+    /// This avoids burning through addresses on discarded builds, but transactions built before
+    /// one of them confirms share a change address. If more than one is broadcast, the address is
+    /// reused and links them together on-chain.
+    ReuseLastUnused,
+}
+
 #[derive(Debug, Clone)]
 pub struct LocalUtxo<K> {
     pub outpoint: OutPoint,
     pub txout: TxOut,
     pub keychain: K,
     pub derivation_index: u32,
+    /// Address of the output, `None` if its script has no address form.
+    pub address: Option<Address>,
+    /// Position of the transaction creating the output in the canonical history.
+    pub chain_position: ChainPosition<ConfirmationBlockTime>,
 }
 
 #[derive(Debug, Clone)]
@@ -232,4 +1121,636 @@ pub struct TransactionDetails {
     pub sent: Amount,
     pub received: Amount,
     pub fee: Option<Amount>,
-}
\ No newline at end of file
+    pub weight: Weight,
+    /// Index of the change output, `None` if the transaction has no change.
+    pub change_vout: Option<u32>,
+    pub label: Option<String>,
+    pub message: Option<String>,
+}
+
+impl TransactionDetails {
+    /// Effective fee rate of the transaction, `None` if the fee is unknown.
+    pub fn fee_rate(&self) -> Option<FeeRate> {
+        if self.weight == Weight::ZERO {
+            return None;
+        }
+        self.fee.map(|fee| fee / self.weight)
+    }
+}
+
+/// Decode a percent-encoded URI component, `None` if it is malformed or not UTF-8.
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            let hex = core::str::from_utf8(&hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::multi_keychain::test_utils::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::Network;
+
+    fn address(seed: &[u8]) -> Address {
+        let script = ScriptBuf::new_p2wpkh(&bitcoin::WPubkeyHash::hash(seed));
+        Address::from_script(&script, Network::Signet).unwrap()
+    }
+
+    fn external_address() -> Address<NetworkUnchecked> {
+        Address::from_script(&external_spk(), Network::Signet)
+            .unwrap()
+            .into_unchecked()
+    }
+
+    #[test]
+    fn max_inputs_caps_coin_selection() {
+        let mut wallet = new_wallet();
+        for height in 1..=5 {
+            receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(10_000), height);
+        }
+
+        let (psbt, _) = wallet
+            .build_tx()
+            .add_recipient_script(external_spk(), Amount::from_sat(35_000))
+            .max_inputs(5)
+            .finish()
+            .unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 4);
+
+        let result = wallet
+            .build_tx()
+            .add_recipient_script(external_spk(), Amount::from_sat(35_000))
+            .max_inputs(3)
+            .finish();
+        assert!(matches!(
+            result,
+            Err(WalletError::TxBuilder(TxBuilderError::InsufficientFunds { .. }))
+        ));
+
+        // draining spends every UTXO regardless of the cap
+        let (psbt, _) = wallet
+            .build_tx()
+            .drain_wallet()
+            .add_recipient_script(external_spk(), Amount::ZERO)
+            .max_inputs(3)
+            .finish()
+            .unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 5);
+    }
+
+    #[test]
+    fn sort_candidates_by_prefers_keychain() {
+        let mut wallet = new_wallet();
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(50_000), 1);
+        let first = receive_confirmed(&mut wallet, Keychain::Internal, Amount::from_sat(20_000), 2);
+        let second =
+            receive_confirmed(&mut wallet, Keychain::Internal, Amount::from_sat(25_000), 3);
+
+        // largest-first would only spend the external coin
+        let (psbt, _) = wallet
+            .build_tx()
+            .add_recipient(external_address(), Amount::from_sat(30_000))
+            .unwrap()
+            .sort_candidates_by(Box::new(|a, b| {
+                (a.keychain != Keychain::Internal, b.txout.value)
+                    .cmp(&(b.keychain != Keychain::Internal, a.txout.value))
+            }))
+            .finish()
+            .unwrap();
+        let spent: Vec<_> = psbt
+            .unsigned_tx
+            .input
+            .iter()
+            .map(|txin| txin.previous_output)
+            .collect();
+        assert_eq!(spent, [second, first]);
+    }
+
+    #[test]
+    fn single_keychain_follows_manual_utxos() {
+        let mut wallet = new_wallet();
+        let external =
+            receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(20_000), 1);
+        let other_external =
+            receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(20_000), 2);
+        let internal =
+            receive_confirmed(&mut wallet, Keychain::Internal, Amount::from_sat(50_000), 3);
+
+        // the internal coin alone would be the fewest inputs
+        let (psbt, _) = wallet
+            .build_tx()
+            .add_recipient(external_address(), Amount::from_sat(30_000))
+            .unwrap()
+            .add_utxo(external)
+            .restrict_to_single_keychain()
+            .finish()
+            .unwrap();
+        let mut spent: Vec<_> = psbt
+            .unsigned_tx
+            .input
+            .iter()
+            .map(|txin| txin.previous_output)
+            .collect();
+        spent.sort();
+        let mut expected = [external, other_external];
+        expected.sort();
+        assert_eq!(spent, expected);
+
+        let result = wallet
+            .build_tx()
+            .add_recipient(external_address(), Amount::from_sat(30_000))
+            .unwrap()
+            .add_utxo(external)
+            .add_utxo(internal)
+            .restrict_to_single_keychain()
+            .finish();
+        assert!(matches!(
+            result,
+            Err(WalletError::TxBuilder(TxBuilderError::MixedKeychains))
+        ));
+    }
+
+    #[test]
+    fn drain_to_many_splits_by_weight() {
+        let mut wallet = new_wallet();
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(100_000), 1);
+        let (first, second) = (address(b"first"), address(b"second"));
+
+        let (psbt, details) = wallet
+            .build_tx()
+            .drain_to_many(alloc::vec![(first.clone(), 1.0 / 3.0), (second.clone(), 2.0 / 3.0)])
+            .finish()
+            .unwrap();
+        let outputs = &psbt.unsigned_tx.output;
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(details.change_vout, None);
+
+        let total = Amount::from_sat(100_000) - details.fee.unwrap();
+        assert_eq!(details.sent, total);
+        assert_eq!(outputs[0].script_pubkey, first.script_pubkey());
+        assert_eq!(outputs[1].script_pubkey, second.script_pubkey());
+        let second_value = (total.to_sat() as f64 * (2.0 / 3.0)) as u64;
+        assert_eq!(outputs[1].value.to_sat(), second_value);
+        // the rounding remainder goes to the first output
+        assert_eq!(outputs[0].value.to_sat(), total.to_sat() - second_value);
+        assert!(outputs[0].value.to_sat() > (total.to_sat() as f64 / 3.0) as u64);
+    }
+
+    #[test]
+    fn drain_to_many_validates_weights() {
+        let mut wallet = new_wallet();
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(100_000), 1);
+
+        let invalid_weights = [
+            alloc::vec![(address(b"first"), 0.5), (address(b"second"), 0.4)],
+            alloc::vec![(address(b"first"), 1.5), (address(b"second"), -0.5)],
+            alloc::vec![(address(b"first"), f64::NAN)],
+        ];
+        for destinations in invalid_weights {
+            let result = wallet.build_tx().drain_to_many(destinations).finish();
+            assert!(matches!(
+                result,
+                Err(WalletError::TxBuilder(TxBuilderError::InvalidRecipient))
+            ));
+        }
+
+        let result = wallet.build_tx().drain_to_many(alloc::vec![]).finish();
+        assert!(matches!(
+            result,
+            Err(WalletError::TxBuilder(TxBuilderError::NoRecipients))
+        ));
+    }
+
+    #[test]
+    fn drain_to_many_rejects_dust_outputs() {
+        let mut wallet = new_wallet();
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(100_000), 1);
+
+        let result = wallet
+            .build_tx()
+            .drain_to_many(alloc::vec![(address(b"first"), 0.999), (address(b"second"), 0.001)])
+            .finish();
+        assert!(matches!(
+            result,
+            Err(WalletError::TxBuilder(TxBuilderError::DustOutput))
+        ));
+    }
+
+    #[test]
+    fn taproot_key_path_weight_matches_signed_tx() {
+        let mut wallet = new_wallet_with(&[(Keychain::External, TR_RECEIVE)]);
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(100_000), 1);
+        let fee_rate = FeeRate::from_sat_per_vb(5).unwrap();
+
+        let (mut psbt, details) = wallet
+            .build_tx()
+            .add_recipient(external_address(), Amount::from_sat(30_000))
+            .unwrap()
+            .fee_rate(fee_rate)
+            .finish()
+            .unwrap();
+        assert!(details.change_vout.is_some());
+
+        assert!(wallet.sign(&mut psbt).unwrap());
+        assert!(wallet.finalize_psbt(&mut psbt).unwrap());
+        let tx = wallet.extract_tx(psbt).unwrap();
+        assert_eq!(details.weight, tx.weight());
+        assert_eq!(details.weight.to_vbytes_ceil(), tx.vsize() as u64);
+
+        // the fee covers every output, including the change
+        let fee = details.fee.unwrap();
+        let min_fee = fee_rate.fee_wu(tx.weight()).unwrap();
+        assert!(fee >= min_fee);
+        assert!(fee - min_fee <= Amount::from_sat(5));
+    }
+
+    #[test]
+    fn legacy_inputs_have_no_segwit_marker() {
+        const PKH_RECEIVE: &str = "pkh(tprv8ZgxMBicQKsPdy6LMhUtFHAgpocR8GC6QmwMSFpZs7h6Eziw3SpThFfczTDh5rW2krkqffa11UpX3XkeTTB2FvzZKWXqPY54Y6Rq4AQ5R8L/44'/1'/0'/0/*)";
+        let mut wallet = new_wallet_with(&[(Keychain::External, PKH_RECEIVE)]);
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(100_000), 1);
+
+        let (mut psbt, details) = wallet
+            .build_tx()
+            .add_recipient(external_address(), Amount::from_sat(30_000))
+            .unwrap()
+            .finish()
+            .unwrap();
+        let input_weight = wallet.input_weight(&Keychain::External).unwrap();
+        assert_eq!(
+            details.weight,
+            psbt.unsigned_tx.weight() + input_weight - TxIn::default().legacy_weight()
+        );
+
+        assert!(wallet.sign(&mut psbt).unwrap());
+        assert!(wallet.finalize_psbt(&mut psbt).unwrap());
+        let tx = wallet.extract_tx(psbt).unwrap();
+        assert!(tx.input.iter().all(|txin| txin.witness.is_empty()));
+        // only the worst-case signature size is overestimated
+        assert!(tx.weight() <= details.weight);
+        assert!(details.weight - tx.weight() <= Weight::from_wu(2 * 4 + 1));
+    }
+
+    #[test]
+    fn change_output_pays_for_its_weight() {
+        let mut wallet = new_wallet();
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(100_000), 1);
+        let fee_rate = FeeRate::from_sat_per_vb(20).unwrap();
+
+        let (psbt, details) = wallet
+            .build_tx()
+            .add_recipient(external_address(), Amount::from_sat(30_000))
+            .unwrap()
+            .fee_rate(fee_rate)
+            .finish()
+            .unwrap();
+        let change_vout = details.change_vout.unwrap() as usize;
+        assert_eq!(psbt.unsigned_tx.output.len(), 2);
+
+        let fee = details.fee.unwrap();
+        assert!(fee >= fee_rate.fee_wu(details.weight).unwrap());
+        let change = psbt.unsigned_tx.output[change_vout].value;
+        assert_eq!(Amount::from_sat(100_000) - Amount::from_sat(30_000) - fee, change);
+    }
+
+    #[test]
+    fn select_coins_skips_uneconomical_utxos() {
+        let mut wallet = new_wallet();
+        let dust = receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(200), 1);
+        let funds =
+            receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(100_000), 2);
+        let fee_rate = FeeRate::from_sat_per_vb(50).unwrap();
+
+        // spending the 200 sat output costs more than it is worth at 50 sat/vB
+        let (psbt, _) = wallet
+            .build_tx()
+            .add_recipient(external_address(), Amount::from_sat(10_000))
+            .unwrap()
+            .fee_rate(fee_rate)
+            .sort_candidates_by(Box::new(|a, b| a.txout.value.cmp(&b.txout.value)))
+            .finish()
+            .unwrap();
+        let spent: Vec<_> = psbt
+            .unsigned_tx
+            .input
+            .iter()
+            .map(|txin| txin.previous_output)
+            .collect();
+        assert_eq!(spent, [funds]);
+
+        // it isn't counted as available either, even when draining
+        let (psbt, _) = wallet
+            .build_tx()
+            .drain_to_many(alloc::vec![(address(b"first"), 1.0)])
+            .fee_rate(fee_rate)
+            .finish()
+            .unwrap();
+        assert!(psbt.unsigned_tx.input.iter().all(|txin| txin.previous_output != dust));
+    }
+
+    #[test]
+    fn select_coins_fails_with_only_uneconomical_utxos() {
+        let mut wallet = new_wallet();
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(200), 1);
+
+        let result = wallet
+            .build_tx()
+            .add_recipient(external_address(), Amount::from_sat(1_000))
+            .unwrap()
+            .fee_rate(FeeRate::from_sat_per_vb(50).unwrap())
+            .finish();
+        assert!(matches!(
+            result,
+            Err(WalletError::TxBuilder(TxBuilderError::InsufficientFunds { available: 0, .. }))
+        ));
+    }
+
+    #[test]
+    fn immature_coinbase_is_not_spendable() {
+        let mut wallet = new_wallet();
+        let (_, address) = wallet.reveal_next_address(Keychain::External).unwrap();
+        let coinbase = Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: alloc::vec![TxIn {
+                previous_output: OutPoint::null(),
+                ..Default::default()
+            }],
+            output: alloc::vec![TxOut {
+                value: Amount::from_sat(100_000),
+                script_pubkey: address.script_pubkey(),
+            }],
+        };
+        confirm_tx(&mut wallet, coinbase, 1);
+        insert_checkpoint(&mut wallet, 99);
+
+        let result = wallet
+            .build_tx()
+            .add_recipient(external_address(), Amount::from_sat(10_000))
+            .unwrap()
+            .finish();
+        assert!(matches!(
+            result,
+            Err(WalletError::TxBuilder(TxBuilderError::ImmatureCoinbase))
+        ));
+
+        // spendable once it has 100 confirmations
+        insert_checkpoint(&mut wallet, 100);
+        wallet
+            .build_tx()
+            .add_recipient(external_address(), Amount::from_sat(10_000))
+            .unwrap()
+            .finish()
+            .unwrap();
+    }
+
+    #[test]
+    fn add_utxo_is_always_spent() {
+        let mut wallet = new_wallet();
+        let small = receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(5_000), 1);
+        let large =
+            receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(100_000), 2);
+
+        // largest-first would spend the large output alone
+        let (psbt, _) = wallet
+            .build_tx()
+            .add_recipient(external_address(), Amount::from_sat(10_000))
+            .unwrap()
+            .add_utxo(small)
+            .finish()
+            .unwrap();
+        let spent: Vec<_> = psbt
+            .unsigned_tx
+            .input
+            .iter()
+            .map(|txin| txin.previous_output)
+            .collect();
+        assert_eq!(spent, [small, large]);
+    }
+
+    #[test]
+    fn add_utxo_insufficient_funds() {
+        let mut wallet = new_wallet();
+        let utxo = receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(5_000), 1);
+
+        let result = wallet
+            .build_tx()
+            .add_recipient(external_address(), Amount::from_sat(10_000))
+            .unwrap()
+            .add_utxo(utxo)
+            .finish();
+        assert!(matches!(
+            result,
+            Err(WalletError::TxBuilder(TxBuilderError::InsufficientFunds { available: 5_000, .. }))
+        ));
+    }
+
+    #[test]
+    fn add_utxo_rejects_unknown_outpoint() {
+        let mut wallet = new_wallet();
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(100_000), 1);
+        let unknown = OutPoint::new(bitcoin::Txid::all_zeros(), 7);
+
+        let result = wallet
+            .build_tx()
+            .add_recipient(external_address(), Amount::from_sat(10_000))
+            .unwrap()
+            .add_utxo(unknown)
+            .finish();
+        assert_eq!(
+            result.unwrap_err(),
+            WalletError::TxBuilder(TxBuilderError::UnknownUtxo(unknown))
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn anti_fee_sniping_locktime_is_tip_height() {
+        use bitcoin::secp256k1::rand::rngs::mock::StepRng;
+
+        let mut wallet = new_wallet();
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(100_000), 1);
+        insert_checkpoint(&mut wallet, 500);
+
+        // draws 5 out of 10, so the locktime isn't moved back
+        let mut rng = StepRng::new(1 << 31, 0);
+        let (psbt, _) = wallet
+            .build_tx()
+            .add_recipient(external_address(), Amount::from_sat(10_000))
+            .unwrap()
+            .enable_anti_fee_sniping()
+            .rng(&mut rng)
+            .finish()
+            .unwrap();
+        assert_eq!(
+            psbt.unsigned_tx.lock_time,
+            bitcoin::absolute::LockTime::from_height(500).unwrap()
+        );
+    }
+
+    #[test]
+    fn dust_change_is_added_to_fee() {
+        let mut wallet = new_wallet();
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(100_000), 1);
+        let fee_rate = FeeRate::from_sat_per_vb(2).unwrap();
+
+        // fee of spending the output to a single output, without change
+        let (_, drain) = wallet
+            .build_tx()
+            .drain_to_many(alloc::vec![(address(b"external"), 1.0)])
+            .fee_rate(fee_rate)
+            .finish()
+            .unwrap();
+        let fee_without_change = drain.fee.unwrap();
+
+        // leave 300 sats, which is below the 294 sat dust threshold of the P2WPKH change output
+        // once it has paid the 62 sat fee for its own weight
+        let amount = Amount::from_sat(100_000 - 300) - fee_without_change;
+        let (psbt, details) = wallet
+            .build_tx()
+            .add_recipient(external_address(), amount)
+            .unwrap()
+            .fee_rate(fee_rate)
+            .finish()
+            .unwrap();
+        assert_eq!(psbt.unsigned_tx.output.len(), 1);
+        assert_eq!(details.change_vout, None);
+        assert_eq!(details.fee, Some(fee_without_change + Amount::from_sat(300)));
+
+        // 400 sats leave enough for a P2WPKH change output, though not a P2PKH one
+        let (psbt, details) = wallet
+            .build_tx()
+            .add_recipient(external_address(), amount - Amount::from_sat(100))
+            .unwrap()
+            .fee_rate(fee_rate)
+            .finish()
+            .unwrap();
+        let change_vout = details.change_vout.unwrap() as usize;
+        assert_eq!(psbt.unsigned_tx.output[change_vout].value, Amount::from_sat(400 - 62));
+
+        let result = wallet
+            .build_tx()
+            .add_recipient(external_address(), amount)
+            .unwrap()
+            .fee_rate(fee_rate)
+            .allow_dust_change_to_fee(false)
+            .finish();
+        assert!(matches!(
+            result,
+            Err(WalletError::TxBuilder(TxBuilderError::DustOutput))
+        ));
+    }
+
+    #[test]
+    fn recipients_must_match_wallet_network() {
+        let mut wallet = new_wallet();
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(100_000), 1);
+        let mainnet = Address::from_script(&external_spk(), Network::Bitcoin).unwrap();
+        let signet = Address::from_script(&external_spk(), Network::Signet).unwrap();
+        let amount = Amount::from_sat(10_000);
+        let is_invalid_recipient = |result: Result<_, WalletError>| {
+            matches!(
+                result,
+                Err(WalletError::TxBuilder(TxBuilderError::InvalidRecipient))
+            )
+        };
+
+        assert!(is_invalid_recipient(
+            wallet.build_tx().add_recipient(mainnet.clone().into_unchecked(), amount).map(|_| ())
+        ));
+        assert!(is_invalid_recipient(
+            wallet
+                .build_tx()
+                .add_recipients([(mainnet.clone(), amount)])
+                .finish()
+                .map(|_| ())
+        ));
+        assert!(is_invalid_recipient(
+            wallet
+                .build_tx()
+                .drain_to_many(alloc::vec![(mainnet.clone(), 1.0)])
+                .finish()
+                .map(|_| ())
+        ));
+        assert!(is_invalid_recipient(
+            wallet.estimate_fee(&[(mainnet.clone(), amount)], FeeRate::BROADCAST_MIN).map(|_| ())
+        ));
+        assert!(is_invalid_recipient(
+            wallet.build_sweep(Keychain::External, mainnet.clone()).map(|_| ())
+        ));
+
+        // replacing the recipients drops the invalid one
+        wallet
+            .build_tx()
+            .add_recipients([(mainnet, amount)])
+            .set_recipients(alloc::vec![(signet, amount)])
+            .finish()
+            .unwrap();
+    }
+
+    #[test]
+    fn estimate_fee_matches_built_transaction() {
+        let mut wallet = new_wallet();
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(30_000), 1);
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(50_000), 2);
+        let fee_rate = FeeRate::from_sat_per_vb(5).unwrap();
+
+        for amount in [10_000, 60_000] {
+            let recipients = [
+                (address(b"first"), Amount::from_sat(amount)),
+                (address(b"second"), Amount::from_sat(5_000)),
+            ];
+            let revealed = wallet.index().last_revealed_index(Keychain::External);
+            let estimate = wallet.estimate_fee(&recipients, fee_rate).unwrap();
+            assert_eq!(wallet.index().last_revealed_index(Keychain::External), revealed);
+
+            let (psbt, _) = wallet
+                .build_tx()
+                .add_recipients(recipients)
+                .fee_rate(fee_rate)
+                .finish()
+                .unwrap();
+            assert_eq!(wallet.psbt_fee(&psbt).unwrap(), estimate);
+        }
+    }
+
+    #[test]
+    fn tx_batch_builds_spend_disjoint_inputs() {
+        let mut wallet = new_wallet();
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(20_000), 1);
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(20_000), 2);
+        let inputs = |psbt: &Psbt| -> BTreeSet<OutPoint> {
+            psbt.unsigned_tx.input.iter().map(|txin| txin.previous_output).collect()
+        };
+
+        let mut batch = wallet.tx_batch();
+        let build = |batch: &mut TxBatch<Keychain>| {
+            batch
+                .build_tx()
+                .add_recipient_script(external_spk(), Amount::from_sat(15_000))
+                .finish()
+        };
+        let (first, _) = build(&mut batch).unwrap();
+        let (second, _) = build(&mut batch).unwrap();
+        assert_eq!(inputs(&first).len(), 1);
+        assert_eq!(inputs(&second).len(), 1);
+        assert!(inputs(&first).is_disjoint(&inputs(&second)));
+        let spent: BTreeSet<OutPoint> = inputs(&first).union(&inputs(&second)).cloned().collect();
+        assert_eq!(batch.spent(), &spent);
+
+        assert!(matches!(
+            build(&mut batch),
+            Err(WalletError::TxBuilder(TxBuilderError::InsufficientFunds { available: 0, .. }))
+        ));
+    }
+}
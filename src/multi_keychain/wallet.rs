@@ -1,8 +1,20 @@
 use core::fmt;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 
-use bitcoin::Address;
-use miniscript::{Descriptor, DescriptorPublicKey};
+use bitcoin::constants::COINBASE_MATURITY;
+use bitcoin::{
+    Address, AddressType, Amount, BlockHash, FeeRate, OutPoint, PrivateKey, Psbt, PublicKey,
+    Script, ScriptBuf, SignedAmount, Transaction, TxIn, TxOut, Txid, Weight, Witness,
+};
+use bdk_wallet::descriptor::policy::BuildSatisfaction;
+use bdk_wallet::descriptor::{ExtractPolicy, IntoWalletDescriptor, Policy};
+use bdk_wallet::signer::SignersContainer;
+use bitcoin::psbt::{ExtractTxError, GetKey, SigningKeys};
+use miniscript::descriptor::{KeyMap, KeyMapWrapper};
+use miniscript::psbt::{PsbtExt, PsbtInputSatisfier};
+use miniscript::{DefiniteDescriptorKey, Descriptor, DescriptorPublicKey, Satisfier};
 use crate::bdk_chain::CanonicalizationParams;
 
 #[cfg(feature = "rusqlite")]
@@ -10,27 +22,51 @@ use bdk_chain::rusqlite;
 use bdk_chain::{
     keychain_txout::{KeychainTxOutIndex, DEFAULT_LOOKAHEAD},
     local_chain::LocalChain,
-    CheckPoint, ConfirmationBlockTime, IndexedTxGraph, KeychainIndexed, Merge,
+    tx_graph::CanonicalTx,
+    BlockId, ChainPosition, CheckPoint, ConfirmationBlockTime, IndexedTxGraph, KeychainIndexed, Merge,
 };
 
 use crate::bdk_chain;
 use crate::collections::BTreeMap;
-use crate::multi_keychain::{ChangeSet, KeyRing};
+use crate::multi_keychain::errors::{
+    AddressGenerationError, ChainError, KeyRingError, LabelImportError, PsbtFeeError,
+    SigningError, TxBuilderError, WalletError,
+};
+#[cfg(feature = "async")]
+use crate::multi_keychain::AsyncWalletPersister;
+use crate::multi_keychain::labels::{parse_bip329_record, write_bip329_record};
+use crate::multi_keychain::{keyring, ChangeSet, KeyRing, Labels, WalletPersister};
 
 /// Alias for a [`IndexedTxGraph`].
 type KeychainTxGraph<K> = IndexedTxGraph<ConfirmationBlockTime, KeychainTxOutIndex<K>>;
 
-// This is here for dev purposes and can be made a configurable option as part of the final API.
-const USE_SPK_CACHE: bool = false;
+/// Default for whether the index caches derived script pubkeys, see
+/// [`Wallet::new_with_lookahead`].
+pub const DEFAULT_USE_SPK_CACHE: bool = false;
+
+/// Default highest derivation index that may be revealed, the last non-hardened BIP-32 index.
+/// See [`Wallet::set_max_reveal_index`].
+pub const DEFAULT_MAX_REVEAL_INDEX: u32 = (1 << 31) - 1;
+
+/// Default maximum absolute fee accepted by [`Wallet::extract_tx`].
+pub const DEFAULT_MAX_FEE: Amount = Amount::from_sat(25_000);
 
 /// [`Wallet`] is a structure that stores transaction data that can be indexed by multiple
 /// keychains.
-#[derive(Debug)]
+///
+/// Cloning a [`Wallet`] gives an independent copy, e.g. to build a candidate transaction and
+/// throw it away without revealing addresses or staging changes on the original.
+#[derive(Debug, Clone)]
 pub struct Wallet<K: Ord> {
     keyring: KeyRing<K>,
     chain: LocalChain,
     tx_graph: KeychainTxGraph<K>,
     stage: ChangeSet<K>,
+    max_fee: Amount,
+    canonicalization_params: CanonicalizationParams,
+    use_spk_cache: bool,
+    labels: Labels<K>,
+    max_reveal_index: BTreeMap<K, u32>,
 }
 
 impl<K> Wallet<K>
@@ -38,7 +74,16 @@ where
     K: fmt::Debug + Clone + Ord,
 {
     /// Construct a new [`Wallet`] with the given `keyring`.
-    pub fn new(mut keyring: KeyRing<K>) -> Self {
+    pub fn new(keyring: KeyRing<K>) -> Self {
+        Self::new_with_lookahead(keyring, DEFAULT_LOOKAHEAD, DEFAULT_USE_SPK_CACHE)
+    }
+
+    /// Construct a new [`Wallet`] with the given `keyring`, tracking `lookahead` script pubkeys
+    /// past the last revealed index of each keychain.
+    ///
+    /// With `use_spk_cache` the derived script pubkeys are persisted, which saves deriving them
+    /// again when the wallet is loaded. Both settings are persisted with the wallet.
+    pub fn new_with_lookahead(keyring: KeyRing<K>, lookahead: u32, use_spk_cache: bool) -> Self {
         let network = keyring.network;
 
         let genesis_hash = bitcoin::constants::genesis_block(network).block_hash();
@@ -46,11 +91,10 @@ where
 
         let keyring_changeset = keyring.initial_changeset();
 
-        let mut index = KeychainTxOutIndex::new(DEFAULT_LOOKAHEAD, USE_SPK_CACHE);
-        let descriptors = core::mem::take(&mut keyring.descriptors);
-        for (keychain, desc) in descriptors {
+        let mut index = KeychainTxOutIndex::new(lookahead, use_spk_cache);
+        for (keychain, desc) in keyring.iter() {
             let _inserted = index
-                .insert_descriptor(keychain, desc)
+                .insert_descriptor(keychain.clone(), desc.clone())
                 .expect("err: failed to insert descriptor");
             assert!(_inserted);
         }
@@ -62,6 +106,10 @@ where
             local_chain: chain_changeset,
             tx_graph: bdk_chain::tx_graph::ChangeSet::default(),
             indexer: bdk_chain::keychain_txout::ChangeSet::default(),
+            lookahead: Some(lookahead),
+            use_spk_cache: Some(use_spk_cache),
+            labels: Labels::default(),
+            used_marks: BTreeMap::new(),
         };
 
         Self {
@@ -69,12 +117,18 @@ where
             chain,
             tx_graph,
             stage,
+            max_fee: DEFAULT_MAX_FEE,
+            canonicalization_params: CanonicalizationParams::default(),
+            use_spk_cache,
+            labels: Labels::default(),
+            max_reveal_index: BTreeMap::new(),
         }
     }
 
     /// Construct [`Wallet`] from the provided `changeset`.
     ///
-    /// Will be `None` if the changeset is empty.
+    /// Will be `None` if the changeset is empty, or if the genesis block of the persisted chain
+    /// doesn't belong to the keyring's network.
     pub fn from_changeset(changeset: ChangeSet<K>) -> Option<Self> {
         if changeset.is_empty() {
             return None;
@@ -85,14 +139,21 @@ where
             LocalChain::from_changeset(changeset.local_chain).expect("err: Missing genesis");
 
         // keyring
-        let mut keyring = KeyRing::from_changeset(changeset.keyring)?;
+        let keyring = KeyRing::from_changeset(changeset.keyring)?;
+        let genesis_hash = bitcoin::constants::genesis_block(keyring.network).block_hash();
+        if chain.genesis_hash() != genesis_hash {
+            return None;
+        }
 
         // index
-        let mut index = KeychainTxOutIndex::new(DEFAULT_LOOKAHEAD, USE_SPK_CACHE);
+        // Wallets persisted before these settings were stored used the defaults
+        let lookahead = changeset.lookahead.unwrap_or(DEFAULT_LOOKAHEAD);
+        let use_spk_cache = changeset.use_spk_cache.unwrap_or(DEFAULT_USE_SPK_CACHE);
+        let mut index = KeychainTxOutIndex::new(lookahead, use_spk_cache);
         index.apply_changeset(changeset.indexer);
-        for (keychain, descriptor) in core::mem::take(&mut keyring.descriptors) {
+        for (keychain, descriptor) in keyring.iter() {
             let _inserted = index
-                .insert_descriptor(keychain, descriptor)
+                .insert_descriptor(keychain.clone(), descriptor.clone())
                 .expect("failed to insert descriptor");
             assert!(_inserted);
         }
@@ -100,6 +161,13 @@ where
         // txgraph
         let mut tx_graph = KeychainTxGraph::new(index);
         tx_graph.apply_changeset(changeset.tx_graph.into());
+        for (keychain, marks) in changeset.used_marks {
+            for (index, used) in marks {
+                if used {
+                    tx_graph.index.mark_used(keychain.clone(), index);
+                }
+            }
+        }
 
         let stage = ChangeSet::default();
 
@@ -108,9 +176,53 @@ where
             stage,
             chain,
             keyring,
+            max_fee: DEFAULT_MAX_FEE,
+            canonicalization_params: CanonicalizationParams::default(),
+            use_spk_cache,
+            labels: changeset.labels,
+            max_reveal_index: BTreeMap::new(),
         })
     }
 
+    /// Load a [`Wallet`] from `persister`, or `None` if nothing has been persisted yet.
+    pub fn load<P: WalletPersister<K>>(persister: &mut P) -> Result<Option<Self>, P::Error> {
+        Ok(persister.initialize()?.and_then(Self::from_changeset))
+    }
+
+    /// Persist the staged changes to `persister`, clearing the stage on success.
+    ///
+    /// Returns whether there was anything to persist.
+    pub fn persist<P: WalletPersister<K>>(&mut self, persister: &mut P) -> Result<bool, P::Error> {
+        if self.stage.is_empty() {
+            return Ok(false);
+        }
+        persister.persist(&self.stage)?;
+        self.stage.take();
+        Ok(true)
+    }
+
+    /// Load a [`Wallet`] from an async `persister`, see [`load`](Self::load).
+    #[cfg(feature = "async")]
+    pub async fn load_async<P: AsyncWalletPersister<K>>(
+        persister: &mut P,
+    ) -> Result<Option<Self>, P::Error> {
+        Ok(persister.initialize().await?.and_then(Self::from_changeset))
+    }
+
+    /// Persist the staged changes to an async `persister`, see [`persist`](Self::persist).
+    #[cfg(feature = "async")]
+    pub async fn persist_async<P: AsyncWalletPersister<K>>(
+        &mut self,
+        persister: &mut P,
+    ) -> Result<bool, P::Error> {
+        if self.stage.is_empty() {
+            return Ok(false);
+        }
+        persister.persist(&self.stage).await?;
+        self.stage = ChangeSet::default();
+        Ok(true)
+    }
+
     /// Reveal next default address. Panics if the default implementation of `K` does not match
     /// a keychain contained in this wallet.
     pub fn reveal_next_default_address_unwrap(&mut self) -> KeychainIndexed<K, Address>
@@ -124,38 +236,632 @@ where
     /// Reveal next address from the given `keychain`.
     ///
     /// This may return the last revealed address in case there are none left to reveal.
+    ///
+    /// Returns `None` if the keychain is unknown or past its
+    /// [maximum reveal index](Self::set_max_reveal_index), see
+    /// [`try_reveal_next_address`](Self::try_reveal_next_address) for the reason.
     pub fn reveal_next_address(&mut self, keychain: K) -> Option<KeychainIndexed<K, Address>> {
-        let ((index, spk), index_changeset) =
-            self.tx_graph.index.reveal_next_spk(keychain.clone())?;
+        self.try_reveal_next_address(keychain).ok()
+    }
+
+    /// Reveal next address from the given `keychain`, see
+    /// [`reveal_next_address`](Self::reveal_next_address).
+    ///
+    /// Fails with [`AddressGenerationError::KeychainNotFound`] if the keychain is unknown, and
+    /// [`AddressGenerationError::DerivationLimit`] if the next index is past the keychain's
+    /// [maximum reveal index](Self::set_max_reveal_index).
+    pub fn try_reveal_next_address(
+        &mut self,
+        keychain: K,
+    ) -> Result<KeychainIndexed<K, Address>, AddressGenerationError> {
+        let descriptor = self
+            .keyring
+            .get_descriptor(&keychain)
+            .ok_or(AddressGenerationError::KeychainNotFound)?;
+        let next_index = self
+            .tx_graph
+            .index
+            .last_revealed_index(keychain.clone())
+            .map_or(0, |last_revealed| last_revealed + 1);
+        if descriptor.has_wildcard() && next_index > self.max_reveal_index(&keychain) {
+            return Err(AddressGenerationError::DerivationLimit);
+        }
+
+        let ((index, spk), index_changeset) = self
+            .tx_graph
+            .index
+            .reveal_next_spk(keychain.clone())
+            .ok_or(AddressGenerationError::KeychainNotFound)?;
         let address = Address::from_script(&spk, self.keyring.network)
             .expect("script should have address form");
 
         self.stage(index_changeset);
 
+        Ok(((keychain, index), address))
+    }
+
+    /// Set the highest derivation index that may be revealed for `keychain`, to catch bugs that
+    /// reveal addresses without bound. Defaults to [`DEFAULT_MAX_REVEAL_INDEX`].
+    ///
+    /// This is not persisted.
+    pub fn set_max_reveal_index(&mut self, keychain: K, max_index: u32) {
+        self.max_reveal_index.insert(keychain, max_index);
+    }
+
+    /// Get the highest derivation index that may be revealed for `keychain`.
+    pub fn max_reveal_index(&self, keychain: &K) -> u32 {
+        self.max_reveal_index
+            .get(keychain)
+            .copied()
+            .unwrap_or(DEFAULT_MAX_REVEAL_INDEX)
+    }
+
+    /// Peek the change address [`reveal_next_address`](Self::reveal_next_address) would
+    /// return for `keychain`, without revealing it.
+    ///
+    /// Returns `None` if the keychain is unknown or has no addresses left to reveal.
+    pub fn peek_change_address(&self, keychain: K) -> Option<Address> {
+        self.peek_next_address(keychain).map(|(_, address)| address)
+    }
+
+    /// Derive the script pubkey of `keychain` at `index`, e.g. for outputs without an address
+    /// form.
+    ///
+    /// Returns `None` if the keychain is unknown or can't be derived at `index`. A keychain
+    /// without a wildcard only has index 0.
+    pub fn derive_spk(&self, keychain: K, index: u32) -> Option<ScriptBuf> {
+        let descriptor = self.keyring.get_descriptor(&keychain)?;
+        if !descriptor.has_wildcard() && index > 0 {
+            return None;
+        }
+        descriptor
+            .at_derivation_index(index)
+            .ok()
+            .map(|descriptor| descriptor.script_pubkey())
+    }
+
+    /// Whether the address of `keychain` at `index` is `expected`, e.g. to check an address
+    /// shown on a hardware wallet against the one derived here.
+    ///
+    /// Returns `false` if the keychain is unknown or can't be derived at `index`.
+    pub fn verify_address(&self, keychain: K, index: u32, expected: &Address) -> bool {
+        let spk = match self.derive_spk(keychain, index) {
+            Some(spk) => spk,
+            None => return false,
+        };
+        Address::from_script(&spk, self.keyring.network).map_or(false, |address| &address == expected)
+    }
+
+    /// Peek the next address to be revealed for `keychain` along with its index.
+    ///
+    /// A keychain without a wildcard only has index 0, which is always the next address.
+    pub(crate) fn peek_next_address(&self, keychain: K) -> Option<KeychainIndexed<K, Address>> {
+        let descriptor = self.keyring.get_descriptor(&keychain)?;
+        let index = if descriptor.has_wildcard() {
+            self.tx_graph
+                .index
+                .last_revealed_index(keychain.clone())
+                .map_or(0, |last_revealed| last_revealed + 1)
+        } else {
+            0
+        };
+        let spk = descriptor
+            .at_derivation_index(index)
+            .ok()?
+            .script_pubkey();
+        let address = Address::from_script(&spk, self.keyring.network)
+            .expect("script should have address form");
         Some(((keychain, index), address))
     }
 
+    /// Get the lowest revealed address of `keychain` that is still unused.
+    ///
+    /// A new address is only revealed if every revealed address has been used. Returns `None` if
+    /// the keychain is unknown or that address is past its
+    /// [maximum reveal index](Self::set_max_reveal_index), see
+    /// [`try_next_unused_address`](Self::try_next_unused_address) for the reason.
+    pub fn next_unused_address(&mut self, keychain: K) -> Option<KeychainIndexed<K, Address>> {
+        self.try_next_unused_address(keychain).ok()
+    }
+
+    /// Get the lowest revealed address of `keychain` that is still unused, see
+    /// [`next_unused_address`](Self::next_unused_address).
+    ///
+    /// Fails like [`try_reveal_next_address`](Self::try_reveal_next_address) when a new address
+    /// has to be revealed.
+    pub fn try_next_unused_address(
+        &mut self,
+        keychain: K,
+    ) -> Result<KeychainIndexed<K, Address>, AddressGenerationError> {
+        let unused = self
+            .tx_graph
+            .index
+            .unused_keychain_spks(keychain.clone())
+            .next();
+        match unused {
+            Some((index, spk)) => {
+                let address = Address::from_script(&spk, self.keyring.network)
+                    .expect("script should have address form");
+                Ok(((keychain, index), address))
+            }
+            None => self.try_reveal_next_address(keychain),
+        }
+    }
+
+    /// Iterate over the revealed addresses of `keychain` that are still unused, in ascending
+    /// index order.
+    pub fn unused_addresses(
+        &self,
+        keychain: K,
+    ) -> impl Iterator<Item = KeychainIndexed<K, Address>> + '_ {
+        self.tx_graph
+            .index
+            .unused_keychain_spks(keychain.clone())
+            .map(move |(index, spk)| {
+                let address = Address::from_script(&spk, self.keyring.network)
+                    .expect("script should have address form");
+                ((keychain.clone(), index), address)
+            })
+    }
+
+    /// Reveal addresses from the given `keychain` up to and including `target_index`.
+    ///
+    /// Returns every newly revealed address, which is empty if `target_index` is already
+    /// revealed. The changes are staged as a single changeset.
+    pub fn reveal_addresses_to(
+        &mut self,
+        keychain: K,
+        target_index: u32,
+    ) -> Result<Vec<KeychainIndexed<K, Address>>, WalletError> {
+        if target_index > self.max_reveal_index(&keychain) {
+            return Err(AddressGenerationError::DerivationLimit.into());
+        }
+        let (spks, index_changeset) = self
+            .tx_graph
+            .index
+            .reveal_to_target(keychain.clone(), target_index)
+            .ok_or(AddressGenerationError::KeychainNotFound)?;
+
+        self.stage(index_changeset);
+
+        spks
+            .into_iter()
+            .map(|(index, spk)| -> Result<_, WalletError> {
+                let address = Address::from_script(&spk, self.keyring.network)
+                    .map_err(|_| AddressGenerationError::Descriptor)?;
+                Ok(((keychain.clone(), index), address))
+            })
+            .collect()
+    }
+
+    /// Reveal the addresses paid by the outputs of `psbt` that belong to the wallet, e.g. one
+    /// built by someone else that pays us, so that syncing tracks them.
+    ///
+    /// Outputs are recognized within the revealed range plus lookahead of each keychain, and
+    /// those that don't belong to the wallet are skipped. The index changes are staged.
+    ///
+    /// Fails with [`AddressGenerationError::DerivationLimit`] if an output is past its keychain's
+    /// [maximum reveal index](Self::set_max_reveal_index), in which case nothing is revealed.
+    pub fn register_psbt_outputs(&mut self, psbt: &Psbt) -> Result<(), AddressGenerationError> {
+        let targets: Vec<(K, u32)> = psbt
+            .unsigned_tx
+            .output
+            .iter()
+            .filter_map(|txout| self.derivation_of_spk(&txout.script_pubkey))
+            .collect();
+        if targets
+            .iter()
+            .any(|(keychain, index)| *index > self.max_reveal_index(keychain))
+        {
+            return Err(AddressGenerationError::DerivationLimit);
+        }
+        for (keychain, index) in targets {
+            if let Some((_, index_changeset)) =
+                self.tx_graph.index.reveal_to_target(keychain, index)
+            {
+                self.stage(index_changeset);
+            }
+        }
+        Ok(())
+    }
+
+    /// Mark the address at `index` of `keychain` as used, e.g. because it was handed out
+    /// off-chain.
+    ///
+    /// Returns whether the address was newly marked. This only has an effect on addresses that
+    /// are already revealed and unused. The mark is staged, so it survives a reload once the
+    /// wallet is persisted.
+    pub fn mark_used(&mut self, keychain: K, index: u32) -> bool {
+        let marked = self.tx_graph.index.mark_used(keychain.clone(), index);
+        if marked {
+            self.stage_used_mark(keychain, index, true);
+        }
+        marked
+    }
+
+    /// Undo the effect of [`mark_used`](Self::mark_used).
+    ///
+    /// Returns whether the address is unused again. Addresses that have been seen on-chain stay
+    /// used.
+    pub fn unmark_used(&mut self, keychain: K, index: u32) -> bool {
+        let unmarked = self.tx_graph.index.unmark_used(keychain.clone(), index);
+        if unmarked {
+            self.stage_used_mark(keychain, index, false);
+        }
+        unmarked
+    }
+
+    fn stage_used_mark(&mut self, keychain: K, index: u32, used: bool) {
+        let mut used_marks = BTreeMap::new();
+        used_marks.insert(keychain, BTreeMap::from([(index, used)]));
+        self.stage(ChangeSet {
+            used_marks,
+            ..Default::default()
+        });
+    }
+
+    /// Label the address of `keychain` at `index`, replacing any previous label.
+    pub fn set_address_label(&mut self, keychain: K, index: u32, label: String) {
+        let mut labels = Labels::default();
+        labels.addresses.entry(keychain).or_default().insert(index, label);
+        self.labels.merge(labels.clone());
+        self.stage(ChangeSet {
+            labels,
+            ..Default::default()
+        });
+    }
+
+    /// Label the transaction with `txid`, replacing any previous label.
+    pub fn set_tx_label(&mut self, txid: Txid, label: String) {
+        let mut labels = Labels::default();
+        labels.txs.insert(txid, label);
+        self.labels.merge(labels.clone());
+        self.stage(ChangeSet {
+            labels,
+            ..Default::default()
+        });
+    }
+
+    /// Get the label of the address of `keychain` at `index`.
+    pub fn address_label(&self, keychain: &K, index: u32) -> Option<&str> {
+        self.labels
+            .addresses
+            .get(keychain)?
+            .get(&index)
+            .map(String::as_str)
+    }
+
+    /// Get the label of the transaction with `txid`.
+    pub fn tx_label(&self, txid: Txid) -> Option<&str> {
+        self.labels.txs.get(&txid).map(String::as_str)
+    }
+
+    /// Export all labels as [BIP-329] JSON lines, e.g. to import them into another wallet.
+    ///
+    /// Labels of addresses whose keychain is no longer in the keyring are skipped.
+    ///
+    /// [BIP-329]: https://github.com/bitcoin/bips/blob/master/bip-0329.mediawiki
+    pub fn export_labels(&self) -> String {
+        let mut out = String::new();
+        for (keychain, labels) in &self.labels.addresses {
+            let descriptor = match self.keyring.get_descriptor(keychain) {
+                Some(descriptor) => descriptor,
+                None => continue,
+            };
+            for (&index, label) in labels {
+                let address = descriptor
+                    .at_derivation_index(index)
+                    .ok()
+                    .and_then(|descriptor| {
+                        Address::from_script(&descriptor.script_pubkey(), self.keyring.network).ok()
+                    });
+                if let Some(address) = address {
+                    write_bip329_record(&mut out, "addr", address, label);
+                }
+            }
+        }
+        for (txid, label) in &self.labels.txs {
+            write_bip329_record(&mut out, "tx", txid, label);
+        }
+        out
+    }
+
+    /// Import [BIP-329] labels, e.g. ones exported with [`export_labels`](Self::export_labels).
+    ///
+    /// Only `addr` and `tx` records are imported, and address labels only for addresses of the
+    /// wallet within the revealed range plus lookahead. Other records, and those without a
+    /// label, are skipped. Fails with [`LabelImportError::InvalidRecord`] if a line isn't a valid
+    /// record, in which case nothing is imported. Returns the number of labels imported, which
+    /// are staged.
+    ///
+    /// [BIP-329]: https://github.com/bitcoin/bips/blob/master/bip-0329.mediawiki
+    pub fn import_labels(&mut self, records: &str) -> Result<usize, LabelImportError> {
+        let mut labels = Labels::default();
+        let mut imported = 0;
+        for (line, record) in (1..).zip(records.lines()) {
+            if record.trim().is_empty() {
+                continue;
+            }
+            let invalid = LabelImportError::InvalidRecord { line };
+            let (kind, reference, label) =
+                parse_bip329_record(record).ok_or_else(|| invalid.clone())?;
+            let label = match label {
+                Some(label) => label,
+                None => continue,
+            };
+            match kind.as_str() {
+                "tx" => {
+                    let txid = reference.parse::<Txid>().map_err(|_| invalid)?;
+                    labels.txs.insert(txid, label);
+                }
+                "addr" => {
+                    let address = reference
+                        .parse::<Address<bitcoin::address::NetworkUnchecked>>()
+                        .map_err(|_| invalid)?;
+                    let derivation = address
+                        .require_network(self.keyring.network)
+                        .ok()
+                        .and_then(|address| self.derivation_of_spk(&address.script_pubkey()));
+                    let (keychain, index) = match derivation {
+                        Some(derivation) => derivation,
+                        None => continue,
+                    };
+                    labels.addresses.entry(keychain).or_default().insert(index, label);
+                }
+                _ => continue,
+            }
+            imported += 1;
+        }
+
+        self.labels.merge(labels.clone());
+        self.stage(ChangeSet {
+            labels,
+            ..Default::default()
+        });
+        Ok(imported)
+    }
+
+    /// Whether `script` belongs to one of the wallet's keychains.
+    ///
+    /// Only scripts within the revealed range plus lookahead are recognized.
+    pub fn is_mine(&self, script: &Script) -> bool {
+        self.derivation_of_spk(script).is_some()
+    }
+
+    /// Get the keychain and derivation index of `script`, if it belongs to the wallet.
+    pub fn derivation_of_spk(&self, script: &Script) -> Option<(K, u32)> {
+        self.tx_graph.index.index_of_spk(script.into()).cloned()
+    }
+
     /// Iterate over `(keychain descriptor)` pairs contained in this wallet.
     pub fn keychains(
         &self,
     ) -> impl DoubleEndedIterator<Item = (K, &Descriptor<DescriptorPublicKey>)> {
-        self.tx_graph.index.keychains()
+        self.keyring
+            .iter()
+            .map(|(keychain, descriptor)| (keychain.clone(), descriptor))
     }
 
     /// Compute the balance.
     pub fn balance(&self) -> bdk_chain::Balance {
-        use bdk_chain::CanonicalizationParams;
         let chain = &self.chain;
         let outpoints = self.tx_graph.index.outpoints().clone();
         self.tx_graph.graph().balance(
             chain,
             chain.tip().block_id(),
-            CanonicalizationParams::default(),
+            self.canonicalization_params.clone(),
             outpoints,
             |_, _| false,
         )
     }
 
+    /// Get the balance, counting outputs as confirmed only once they have at least `min_conf`
+    /// confirmations at the current tip.
+    ///
+    /// Outputs confirmed fewer times are reported as trusted pending, as they are in a block but
+    /// not yet final. With a `min_conf` of 1 this is the same as [`balance`](Self::balance).
+    pub fn balance_with_confirmations(&self, min_conf: u32) -> bdk_chain::Balance {
+        let tip_height = self.chain.tip().height();
+        let mut balance = bdk_chain::Balance::default();
+        let unspents = self.tx_graph.graph().filter_chain_unspents(
+            &self.chain,
+            self.chain.tip().block_id(),
+            self.canonicalization_params.clone(),
+            self.tx_graph.index.outpoints().iter().cloned(),
+        );
+        for (_, txout) in unspents {
+            let value = txout.txout.value;
+            match &txout.chain_position {
+                ChainPosition::Confirmed { anchor, .. } => {
+                    let confirmations = tip_height.saturating_sub(anchor.block_id.height) + 1;
+                    if !txout.is_mature(tip_height) {
+                        balance.immature += value;
+                    } else if confirmations >= min_conf {
+                        balance.confirmed += value;
+                    } else {
+                        balance.trusted_pending += value;
+                    }
+                }
+                ChainPosition::Unconfirmed { .. } => balance.untrusted_pending += value,
+            }
+        }
+        balance
+    }
+
+    /// Iterate over the transactions in the wallet's canonical history.
+    pub fn transactions(
+        &self,
+    ) -> impl Iterator<Item = CanonicalTx<'_, Arc<Transaction>, ConfirmationBlockTime>> {
+        self.tx_graph.graph().list_canonical_txs(
+            &self.chain,
+            self.chain.tip().block_id(),
+            self.canonicalization_params.clone(),
+        )
+    }
+
+    /// Get how far the wallet is synced.
+    pub fn sync_status(&self) -> SyncStatus {
+        let tip = self.chain.tip().block_id();
+        let latest_seen_unconfirmed = self
+            .transactions()
+            .filter_map(|canonical_tx| match canonical_tx.chain_position {
+                ChainPosition::Unconfirmed { last_seen, .. } => last_seen,
+                ChainPosition::Confirmed { .. } => None,
+            })
+            .max();
+        SyncStatus {
+            tip_height: tip.height,
+            tip_hash: tip.hash,
+            latest_seen_unconfirmed,
+        }
+    }
+
+    /// Get the position of the transaction with `txid` in the wallet's canonical history.
+    ///
+    /// Returns `None` if the transaction is not in the graph or is not canonical, e.g. because it
+    /// was replaced or evicted.
+    pub fn tx_chain_position(&self, txid: Txid) -> Option<ChainPosition<ConfirmationBlockTime>> {
+        self.transactions()
+            .find(|canonical_tx| canonical_tx.tx_node.txid == txid)
+            .map(|canonical_tx| canonical_tx.chain_position)
+    }
+
+    /// Get the number of confirmations of the output at `outpoint` at the current tip.
+    ///
+    /// Returns `None` if the output is unknown or its transaction is not confirmed.
+    pub fn confirmations(&self, outpoint: OutPoint) -> Option<u32> {
+        self.tx_graph.graph().get_txout(outpoint)?;
+        match self.tx_chain_position(outpoint.txid)? {
+            ChainPosition::Confirmed { anchor, .. } => {
+                let tip_height = self.chain.tip().height();
+                Some(tip_height.saturating_sub(anchor.block_id.height) + 1)
+            }
+            ChainPosition::Unconfirmed { .. } => None,
+        }
+    }
+
+    /// Get the number of blocks until the output at `outpoint` can be spent.
+    ///
+    /// This is 0 for confirmed outputs, except coinbase outputs which need
+    /// [`COINBASE_MATURITY`] confirmations. Returns `None` if the output is unknown or its
+    /// transaction is not confirmed.
+    pub fn blocks_until_spendable(&self, outpoint: OutPoint) -> Option<u32> {
+        let confirmations = self.confirmations(outpoint)?;
+        let tx = self.tx_graph.graph().get_tx(outpoint.txid)?;
+        if tx.is_coinbase() {
+            Some(COINBASE_MATURITY.saturating_sub(confirmations))
+        } else {
+            Some(0)
+        }
+    }
+
+    /// Get the transactions that double-spend an input of the transaction with `txid`, e.g. its
+    /// RBF replacements.
+    ///
+    /// Returns an empty list if the transaction is not known.
+    pub fn conflicts(&self, txid: Txid) -> Vec<Txid> {
+        let graph = self.tx_graph.graph();
+        let tx = match graph.get_tx(txid) {
+            Some(tx) => tx,
+            None => return Vec::new(),
+        };
+        let mut conflicts: Vec<Txid> = graph
+            .direct_conflicts(&tx)
+            .map(|(_, conflict)| conflict)
+            .collect();
+        conflicts.sort_unstable();
+        conflicts.dedup();
+        conflicts
+    }
+
+    /// Get which of the transaction with `txid` and its [`conflicts`](Self::conflicts) is in the
+    /// wallet's canonical history, if any.
+    pub fn canonical_conflict(&self, txid: Txid) -> Option<Txid> {
+        let mut candidates = self.conflicts(txid);
+        candidates.push(txid);
+        self.transactions()
+            .map(|canonical_tx| canonical_tx.tx_node.txid)
+            .find(|canonical_txid| candidates.contains(canonical_txid))
+    }
+
+    /// Whether the transaction with `txid` signals replaceability per [BIP-125], i.e. any of its
+    /// inputs has a sequence below `0xfffffffe`.
+    ///
+    /// Returns `None` if the transaction is not known.
+    ///
+    /// [BIP-125]: https://github.com/bitcoin/bips/blob/master/bip-0125.mediawiki
+    pub fn is_rbf_signaling(&self, txid: Txid) -> Option<bool> {
+        let tx = self.tx_graph.graph().get_tx(txid)?;
+        Some(tx.is_explicitly_rbf())
+    }
+
+    /// Total amount ever received by the wallet.
+    ///
+    /// Only the net value of each transaction is counted, so funds the wallet sends to itself
+    /// (including change) are not counted as received.
+    pub fn total_received(&self) -> Amount {
+        self.total_sent_and_received().1
+    }
+
+    /// Total amount ever sent from the wallet, including fees.
+    ///
+    /// Only the net value of each transaction is counted, so funds the wallet sends to itself
+    /// (including change) are not counted as sent.
+    pub fn total_sent(&self) -> Amount {
+        self.total_sent_and_received().0
+    }
+
+    fn total_sent_and_received(&self) -> (Amount, Amount) {
+        let index = &self.tx_graph.index;
+        self.transactions().fold(
+            (Amount::ZERO, Amount::ZERO),
+            |(total_sent, total_received), canonical_tx| {
+                let (sent, received) = index.sent_and_received(&canonical_tx.tx_node.tx, ..);
+                if received > sent {
+                    (total_sent, total_received + (received - sent))
+                } else {
+                    (total_sent + (sent - received), total_received)
+                }
+            },
+        )
+    }
+
+    /// Amount that can be spent right now.
+    ///
+    /// This is the confirmed balance plus trusted pending outputs, excluding immature coinbase
+    /// outputs and untrusted pending outputs.
+    pub fn spendable_balance(&self) -> Amount {
+        let balance = self.balance();
+        balance.confirmed + balance.trusted_pending
+    }
+
+    /// Get the trusted spendable balance, as in [`Balance::trusted_spendable`].
+    ///
+    /// [`Balance::trusted_spendable`]: bdk_chain::Balance::trusted_spendable
+    pub fn trusted_spendable(&self) -> Amount {
+        self.balance().trusted_spendable()
+    }
+
+    /// Compute the fee rate of the transaction with `txid`.
+    ///
+    /// Returns `None` if the transaction is not in the graph or its fee can't be calculated
+    /// because some of the previous outputs it spends are unknown.
+    pub fn tx_feerate(&self, txid: Txid) -> Option<FeeRate> {
+        let graph = self.tx_graph.graph();
+        let tx = graph.get_tx(txid)?;
+        let fee = graph.calculate_fee(&tx).ok()?;
+        Some(fee / tx.weight())
+    }
+
+    /// Whether the transaction with `txid` is likely to confirm soon, i.e. its fee rate meets
+    /// `mempool_min_feerate`.
+    ///
+    /// Returns `false` if the fee rate of the transaction is not known.
+    pub fn tx_confirmation_likely(&self, txid: Txid, mempool_min_feerate: FeeRate) -> bool {
+        self.tx_feerate(txid)
+            .map_or(false, |feerate| feerate >= mempool_min_feerate)
+    }
+
     /// Obtain a reference to the indexed transaction graph.
     pub fn tx_graph(&self) -> &KeychainTxGraph<K> {
         &self.tx_graph
@@ -171,8 +877,18 @@ where
         &self.chain
     }
 
+    /// Obtain a reference to the keyring.
+    pub fn keyring(&self) -> &KeyRing<K> {
+        &self.keyring
+    }
+
     /// Apply update.
-    pub fn apply_update(&mut self, update: impl Into<Update<K>>) {
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ChainError::CannotConnect`] if the update's chain does not connect to the local
+    /// chain, in which case nothing is applied.
+    pub fn apply_update(&mut self, update: impl Into<Update<K>>) -> Result<(), WalletError> {
         let Update {
             chain,
             tx_update,
@@ -186,7 +902,7 @@ where
             changeset.merge(
                 self.chain
                     .apply_update(tip)
-                    .expect("err: failed to apply update to chain")
+                    .map_err(ChainError::from)?
                     .into(),
             );
         }
@@ -201,6 +917,142 @@ where
         changeset.merge(self.tx_graph.apply_update(tx_update).into());
 
         self.stage(changeset);
+        Ok(())
+    }
+
+    /// Insert a checkpoint for `block_id` into the local chain.
+    ///
+    /// `block_id` becomes the new tip: unless the chain already has it, every block at or above
+    /// its height is invalidated before it is inserted, even where the chain is sparse at that
+    /// height, so this can be used to follow the tip across reorgs. The genesis block can never be
+    /// replaced.
+    pub fn insert_checkpoint(&mut self, block_id: BlockId) -> Result<(), WalletError> {
+        let mut changeset = bdk_chain::local_chain::ChangeSet::default();
+
+        if self.chain.get(block_id.height).map(|cp| cp.hash()) == Some(block_id.hash) {
+            return Ok(());
+        }
+        // reorg: invalidate the lowest block at or above the height, and everything above it
+        let lowest_stale = self
+            .chain
+            .tip()
+            .iter()
+            .take_while(|cp| cp.height() >= block_id.height)
+            .last();
+        if let Some(cp) = lowest_stale {
+            changeset.merge(
+                self.chain
+                    .disconnect_from(cp.block_id())
+                    .map_err(|_| ChainError::Reorg {
+                        height: block_id.height,
+                    })?,
+            );
+        }
+
+        changeset.merge(
+            self.chain
+                .insert_block(block_id)
+                .map_err(ChainError::from)?,
+        );
+
+        self.stage(changeset);
+        Ok(())
+    }
+
+    /// Remove every block above `height` from the local chain, e.g. to rescan from there.
+    ///
+    /// The tx graph is left intact, so transactions confirmed in the removed blocks become
+    /// unconfirmed until a subsequent sync confirms them again. Fails with
+    /// [`ChainError::CannotResetGenesis`] if `height` is 0, since that would only keep the genesis
+    /// block.
+    pub fn reset_chain_from(&mut self, height: u32) -> Result<(), WalletError> {
+        if height == 0 {
+            return Err(ChainError::CannotResetGenesis.into());
+        }
+
+        // lowest checkpoint above `height`, disconnecting it removes everything above too
+        let lowest_above = self
+            .chain
+            .tip()
+            .iter()
+            .take_while(|cp| cp.height() > height)
+            .last();
+        if let Some(cp) = lowest_above {
+            let changeset = self
+                .chain
+                .disconnect_from(cp.block_id())
+                .map_err(ChainError::from)?;
+            self.stage(changeset);
+        }
+        Ok(())
+    }
+
+    /// Make sure every keychain in `last_active_indices` tracks scripts up to its last active
+    /// index plus the configured lookahead, by revealing addresses up to that index.
+    ///
+    /// [`apply_update`](Self::apply_update) already does this with the update's last active
+    /// indices. Call this when the transactions of a full scan are applied some other way, e.g.
+    /// with [`apply_unconfirmed_txs`](Self::apply_unconfirmed_txs), before building the next sync
+    /// request, so that the sync covers a full gap past the last used address and doesn't miss
+    /// funds sent there. Addresses are revealed no further than each keychain's
+    /// [maximum reveal index](Self::set_max_reveal_index), and unknown keychains are skipped.
+    pub fn ensure_lookahead(&mut self, last_active_indices: &BTreeMap<K, u32>) {
+        let targets: BTreeMap<K, u32> = last_active_indices
+            .iter()
+            .map(|(keychain, &index)| {
+                (keychain.clone(), index.min(self.max_reveal_index(keychain)))
+            })
+            .collect();
+        let changeset = self.tx_graph.index.reveal_to_target_multi(&targets);
+        self.stage(changeset);
+    }
+
+    /// Apply unconfirmed transactions to the wallet.
+    ///
+    /// Each transaction is inserted into the tx graph along with the time (in seconds since the
+    /// unix epoch) it was last seen in the mempool. This lets the wallet account for transactions
+    /// that are not yet confirmed, such as ones just broadcast by us.
+    pub fn apply_unconfirmed_txs(
+        &mut self,
+        txs: impl IntoIterator<Item = (Arc<Transaction>, u64)>,
+    ) {
+        let changeset = self.tx_graph.batch_insert_unconfirmed(txs);
+        self.stage(changeset);
+    }
+
+    /// Record that `tx` was broadcast at `seen_at` (in seconds since the unix epoch), so that
+    /// the balance reflects it before the next sync.
+    ///
+    /// Returns the txid of the transaction.
+    pub fn record_broadcast(&mut self, tx: Transaction, seen_at: u64) -> Txid {
+        let txid = tx.compute_txid();
+        self.apply_unconfirmed_txs([(Arc::new(tx), seen_at)]);
+        txid
+    }
+
+    /// Evict the unconfirmed transaction with `txid`, e.g. because it was replaced or
+    /// double-spent out of the mempool.
+    ///
+    /// The transaction stays in the graph but is no longer considered canonical, unless it is
+    /// seen again later or confirms. This is a no-op if `txid` is not in the graph.
+    pub fn evict_tx(&mut self, txid: Txid) {
+        let evicted_at = match self.tx_graph.graph().get_tx_node(txid) {
+            Some(tx_node) => tx_node.last_seen.unwrap_or(0),
+            None => return,
+        };
+        let changeset = self.tx_graph.insert_evicted_at(txid, evicted_at);
+        self.stage(changeset);
+    }
+
+    /// Apply transactions evicted from the mempool, each with the time (in seconds since the unix
+    /// epoch) it was found missing.
+    ///
+    /// An evicted transaction is no longer canonical unless it was seen again after it was
+    /// evicted, so the outputs it spent count towards the balance again. Transactions that are not
+    /// in the graph are ignored.
+    pub fn apply_evicted_txs(&mut self, evicted_ats: impl IntoIterator<Item = (Txid, u64)>) {
+        let changeset = self.tx_graph.batch_insert_relevant_evicted_at(evicted_ats);
+        self.stage(changeset);
     }
 
     /// Stages anything that can be converted directly into a [`ChangeSet`].
@@ -216,11 +1068,75 @@ where
             Some(&self.stage)
         }
     }
+
+    /// Get an owned copy of the staged changes if any, leaving them staged.
+    pub fn staged_cloned(&self) -> Option<ChangeSet<K>> {
+        self.staged().cloned()
+    }
+
+    /// Take the staged changes if any, for callers that persist them on their own.
+    pub fn take_staged(&mut self) -> Option<ChangeSet<K>> {
+        self.stage.take()
+    }
+
     /// Build a transaction with the transaction builder
     pub fn build_tx(&mut self) -> crate::multi_keychain::tx_builder::TxBuilder<K> {
         crate::multi_keychain::tx_builder::TxBuilder::new(self)
     }
 
+    /// Start a [`TxBatch`] to build several transactions that don't spend the same outputs.
+    ///
+    /// [`TxBatch`]: crate::multi_keychain::TxBatch
+    pub fn tx_batch(&mut self) -> crate::multi_keychain::tx_builder::TxBatch<K> {
+        crate::multi_keychain::tx_builder::TxBatch::new(self)
+    }
+
+    /// Estimate the fee of a transaction paying `recipients` at `fee_rate`, e.g. to show the fee
+    /// while the user types an amount.
+    ///
+    /// This runs the same coin selection as [`build_tx`](Self::build_tx) but doesn't reveal a
+    /// change address. Fails with [`TxBuilderError::InsufficientFunds`] if the wallet can't fund
+    /// the transaction, and [`TxBuilderError::InvalidRecipient`] if a recipient address isn't
+    /// valid for the wallet's network.
+    pub fn estimate_fee(
+        &self,
+        recipients: &[(Address, Amount)],
+        fee_rate: FeeRate,
+    ) -> Result<Amount, WalletError> {
+        let (_, details) = crate::multi_keychain::tx_builder::TxBuilder::new_estimate(self)
+            .add_recipients(recipients.iter().cloned())
+            .fee_rate(fee_rate)
+            .finish()?;
+        Ok(details.fee.unwrap_or(Amount::ZERO))
+    }
+
+    /// Start building a transaction sending all funds of `keychain` to `to`, e.g. to migrate an
+    /// account.
+    ///
+    /// Only the outputs of `keychain` are spent, and what is left after fees goes to `to`. Fails
+    /// with [`KeyRingError::KeychainNotFound`] if the keychain is unknown,
+    /// [`TxBuilderError::InvalidRecipient`] if `to` isn't valid for the wallet's network, and
+    /// [`TxBuilderError::NoUtxos`] if the keychain has nothing to spend.
+    pub fn build_sweep(
+        &mut self,
+        keychain: K,
+        to: Address,
+    ) -> Result<crate::multi_keychain::tx_builder::TxBuilder<K>, WalletError> {
+        if !self.keyring.contains_keychain(&keychain) {
+            return Err(KeyRingError::KeychainNotFound.into());
+        }
+        if !to.as_unchecked().is_valid_for_network(self.network()) {
+            return Err(TxBuilderError::InvalidRecipient.into());
+        }
+        if self.list_unspent_for_keychain(&keychain).is_empty() {
+            return Err(TxBuilderError::NoUtxos.into());
+        }
+        Ok(self
+            .build_tx()
+            .prefer_keychain(keychain)
+            .drain_to_many(alloc::vec![(to, 1.0)]))
+    }
+
     /// List all available keychains
     pub fn list_keychains(&self) -> Vec<K> {
         self.keyring.descriptors.keys().cloned().collect()
@@ -231,10 +1147,78 @@ where
         self.keyring.descriptors.get(keychain)
     }
 
-    /// Remove a keychain from the wallet
-    pub fn remove_keychain(&mut self, keychain: &K) -> bool {
-        self.keyring.descriptors.remove(keychain).is_some()
-    }
+    /// Add the secret keys of `keychain` from `descriptor`, its descriptor with private keys, so
+    /// that [`sign`](Self::sign) can sign for it.
+    ///
+    /// A loaded wallet is watch-only, as secret keys are never persisted. See
+    /// [`KeyRing::add_keymap`] for the errors.
+    pub fn add_keymap(
+        &mut self,
+        keychain: K,
+        descriptor: impl IntoWalletDescriptor,
+    ) -> Result<(), WalletError> {
+        Ok(self.keyring.add_keymap(keychain, descriptor)?)
+    }
+
+    /// Remove a keychain from the wallet.
+    ///
+    /// Fails with [`KeyRingError::KeychainHasFunds`] if the keychain still has unspent outputs,
+    /// since the wallet would lose track of them. Use
+    /// [`force_remove_keychain`](Self::force_remove_keychain) to remove it regardless.
+    pub fn remove_keychain(&mut self, keychain: &K) -> Result<(), WalletError> {
+        if !self.list_unspent_for_keychain(keychain).is_empty() {
+            return Err(KeyRingError::KeychainHasFunds.into());
+        }
+        self.force_remove_keychain(keychain)
+    }
+
+    /// Remove a keychain from the wallet even if it still has unspent outputs.
+    ///
+    /// The keychain's address labels and used marks are removed along with it.
+    pub fn force_remove_keychain(&mut self, keychain: &K) -> Result<(), WalletError> {
+        if !self.keyring.remove_keychain(keychain) {
+            return Err(KeyRingError::KeychainNotFound.into());
+        }
+
+        // Addresses of the remaining keychains that are used, to restore any marks
+        let mut used = Vec::new();
+        for (revealed, last_revealed) in self.tx_graph.index.last_revealed_indices() {
+            if &revealed == keychain {
+                continue;
+            }
+            for index in 0..=last_revealed {
+                if self.tx_graph.index.is_used(revealed.clone(), index) {
+                    used.push((revealed.clone(), index));
+                }
+            }
+        }
+
+        // The index can't forget a descriptor, so rebuild it from the remaining ones
+        let changeset = self.tx_graph.initial_changeset();
+        let mut index = KeychainTxOutIndex::new(self.tx_graph.index.lookahead(), self.use_spk_cache);
+        for (keychain, descriptor) in self.keyring.iter() {
+            let _inserted = index
+                .insert_descriptor(keychain.clone(), descriptor.clone())
+                .expect("failed to insert descriptor");
+            assert!(_inserted);
+        }
+        let mut tx_graph = KeychainTxGraph::new(index);
+        tx_graph.apply_changeset(changeset);
+        for (keychain, index) in used {
+            tx_graph.index.mark_used(keychain, index);
+        }
+        self.tx_graph = tx_graph;
+        self.labels.addresses.remove(keychain);
+
+        let mut keyring_changeset = keyring::ChangeSet::default();
+        keyring_changeset.removed.insert(keychain.clone());
+        self.stage(ChangeSet {
+            keyring: keyring_changeset,
+            ..Default::default()
+        });
+
+        Ok(())
+    }
 
     /// Check if a keychain exists
     pub fn has_keychain(&self, keychain: &K) -> bool {
@@ -251,6 +1235,11 @@ where
         self.keyring.network
     }
 
+    /// Get the genesis block hash of the wallet's chain
+    pub fn genesis_hash(&self) -> BlockHash {
+        self.chain.genesis_hash()
+    }
+
     /// Validate all keychains in the wallet
     pub fn validate_keychains(&self) -> Result<(), crate::multi_keychain::errors::WalletError> {
         self.keyring.validate().map_err(Into::into)
@@ -258,11 +1247,10 @@ where
 
     /// Get balance breakdown by keychain
     pub fn balance_by_keychain(&self) -> crate::collections::BTreeMap<K, bdk_chain::Balance> {
-        use bdk_chain::CanonicalizationParams;
 
         let chain = &self.chain;
         let tip = chain.tip().block_id();
-        let params = CanonicalizationParams::default();
+        let params = self.canonicalization_params.clone();
 
         let mut balances = crate::collections::BTreeMap::new();
 
@@ -288,6 +1276,469 @@ where
         balances
     }
 
+    /// Get UTXO and address usage statistics for each keychain.
+    pub fn keychain_stats(&self) -> BTreeMap<K, KeychainStats> {
+        let index = &self.tx_graph.index;
+        let mut stats: BTreeMap<K, KeychainStats> = self
+            .keyring
+            .keychains()
+            .map(|keychain| {
+                let keychain_stats = KeychainStats {
+                    last_revealed_index: index.last_revealed_index(keychain.clone()),
+                    used_count: index
+                        .revealed_keychain_spks(keychain.clone())
+                        .filter(|(i, _)| index.is_used(keychain.clone(), *i))
+                        .count(),
+                    ..Default::default()
+                };
+                (keychain.clone(), keychain_stats)
+            })
+            .collect();
+
+        let unspents = self.tx_graph.graph().filter_chain_unspents(
+            &self.chain,
+            self.chain.tip().block_id(),
+            self.canonicalization_params.clone(),
+            index.outpoints().iter().cloned(),
+        );
+        for ((keychain, _), txout) in unspents {
+            if let Some(keychain_stats) = stats.get_mut(&keychain) {
+                keychain_stats.utxo_count += 1;
+                keychain_stats.total_value += txout.txout.value;
+            }
+        }
+
+        stats
+    }
+
+    /// Get the type of the addresses of `keychain`.
+    ///
+    /// Returns `None` if the keychain is unknown or its descriptor has no address form, such as
+    /// a bare script.
+    pub fn keychain_address_type(&self, keychain: &K) -> Option<AddressType> {
+        self.keyring
+            .get_descriptor(keychain)?
+            .at_derivation_index(0)
+            .ok()?
+            .address(self.keyring.network)
+            .ok()?
+            .address_type()
+    }
+
+    /// Get the spending policy of the descriptor of `keychain`.
+    ///
+    /// The [`Policy`] reports the keys and signature thresholds involved, and any relative or
+    /// absolute timelocks. Returns `None` if the keychain is unknown or its policy can't be
+    /// extracted.
+    pub fn keychain_policy(&self, keychain: &K) -> Option<Policy> {
+        let descriptor = self.keyring.get_descriptor(keychain)?;
+        descriptor
+            .extract_policy(
+                &SignersContainer::default(),
+                BuildSatisfaction::None,
+                &self.keyring.secp,
+            )
+            .ok()
+            .flatten()
+    }
+
+    /// Sign the inputs of `psbt` with the secret keys held by the keyring.
+    ///
+    /// Fails with [`SigningError::MissingPrivateKey`] without touching `psbt` if the keyring is
+    /// [watch-only](KeyRing::is_watch_only). Otherwise behaves like [`sign_with`](Self::sign_with).
+    pub fn sign(&self, psbt: &mut Psbt) -> Result<bool, SigningError> {
+        if self.keyring.is_watch_only() {
+            return Err(SigningError::MissingPrivateKey);
+        }
+        let keymap: KeyMap = self
+            .keyring
+            .keymaps
+            .values()
+            .flat_map(|keymap| keymap.clone())
+            .collect();
+        self.sign_psbt(psbt, &KeyMapWrapper::from(keymap))
+    }
+
+    /// Sign the inputs of `psbt` that any of the externally held `keys` can sign for.
+    ///
+    /// Inputs none of the keys belong to are left untouched. Returns whether any input was
+    /// signed, and fails with [`SigningError::SigningFailed`] if nothing was signed because
+    /// signing errored.
+    pub fn sign_with(&self, psbt: &mut Psbt, keys: &[PrivateKey]) -> Result<bool, SigningError> {
+        let secp = &self.keyring.secp;
+        let keys: BTreeMap<PublicKey, PrivateKey> = keys
+            .iter()
+            .map(|key| (key.public_key(secp), *key))
+            .collect();
+        self.sign_psbt(psbt, &keys)
+    }
+
+    fn sign_psbt<G: GetKey>(&self, psbt: &mut Psbt, keys: &G) -> Result<bool, SigningError> {
+        let (used, failed) = match psbt.sign(keys, &self.keyring.secp) {
+            Ok(used) => (used, false),
+            Err((used, errors)) => (used, !errors.is_empty()),
+        };
+        let signed = used.values().any(|signing_keys| match signing_keys {
+            SigningKeys::Ecdsa(keys) => !keys.is_empty(),
+            SigningKeys::Schnorr(keys) => !keys.is_empty(),
+        });
+        if !signed && failed {
+            return Err(SigningError::SigningFailed);
+        }
+        Ok(signed)
+    }
+
+    /// Compute the fee paid by `psbt`, whether it was built by this wallet or elsewhere.
+    ///
+    /// The value of each input is taken from the PSBT, or the wallet's transactions. Fails with
+    /// [`PsbtFeeError::MissingUtxo`] if it's unknown for any input, and
+    /// [`PsbtFeeError::NegativeFee`] if the outputs spend more than the inputs provide.
+    pub fn psbt_fee(&self, psbt: &Psbt) -> Result<Amount, PsbtFeeError> {
+        let mut input_value = Amount::ZERO;
+        for index in 0..psbt.inputs.len() {
+            input_value += self
+                .psbt_input_txout(psbt, index)
+                .ok_or(PsbtFeeError::MissingUtxo(index))?
+                .value;
+        }
+        let output_value: Amount = psbt.unsigned_tx.output.iter().map(|txout| txout.value).sum();
+        input_value
+            .checked_sub(output_value)
+            .ok_or(PsbtFeeError::NegativeFee)
+    }
+
+    /// Get the output spent by the input at `index` of `psbt`, from the PSBT or the wallet's
+    /// transactions.
+    fn psbt_input_txout(&self, psbt: &Psbt, index: usize) -> Option<TxOut> {
+        let input = psbt.inputs.get(index)?;
+        let prevout = psbt.unsigned_tx.input.get(index)?.previous_output;
+        input
+            .witness_utxo
+            .clone()
+            .or_else(|| {
+                input
+                    .non_witness_utxo
+                    .as_ref()?
+                    .output
+                    .get(prevout.vout as usize)
+                    .cloned()
+            })
+            .or_else(|| self.tx_graph.graph().get_txout(prevout).cloned())
+    }
+
+    /// Summarize what `psbt` does for the user to confirm before signing.
+    ///
+    /// Outputs paying the wallet are change if the PSBT spends any of the wallet's outputs, and
+    /// received otherwise. The output spent by each input is taken from the PSBT, or the wallet's
+    /// transactions. Fails with [`SigningError::MissingUtxo`] if it's unknown, as the fee can't be
+    /// computed then.
+    pub fn describe_psbt(&self, psbt: &Psbt) -> Result<PsbtSummary<K>, SigningError> {
+        let mut input_value = Amount::ZERO;
+        let mut sent = Amount::ZERO;
+        for index in 0..psbt.inputs.len() {
+            let txout = self
+                .psbt_input_txout(psbt, index)
+                .ok_or(SigningError::MissingUtxo(index))?;
+            input_value += txout.value;
+            if self.is_mine(&txout.script_pubkey) {
+                sent += txout.value;
+            }
+        }
+
+        let spends_wallet = sent > Amount::ZERO;
+        let mut received = Amount::ZERO;
+        let outputs = psbt
+            .unsigned_tx
+            .output
+            .iter()
+            .map(|txout| {
+                let kind = match self.derivation_of_spk(&txout.script_pubkey) {
+                    Some((keychain, index)) => {
+                        received += txout.value;
+                        if spends_wallet {
+                            OutputKind::Change(keychain, index)
+                        } else {
+                            OutputKind::Mine(keychain, index)
+                        }
+                    }
+                    None => OutputKind::External,
+                };
+                (txout.clone(), kind)
+            })
+            .collect::<Vec<_>>();
+
+        let output_value: Amount = psbt.unsigned_tx.output.iter().map(|txout| txout.value).sum();
+        let fee = input_value
+            .checked_sub(output_value)
+            .ok_or(SigningError::NegativeFee)?;
+
+        Ok(PsbtSummary {
+            outputs,
+            fee,
+            sent,
+            received,
+        })
+    }
+
+    /// Finalize a signed `psbt`, building the final `script_sig` and witness of each input.
+    ///
+    /// Inputs spending the wallet's outputs are updated with their descriptor before being
+    /// finalized, and fail with [`SigningError::SigningFailed`] if they lack the signatures to
+    /// satisfy it. Foreign inputs that can't be finalized are left as they are, and inputs that
+    /// are already finalized, e.g. a counterparty's in a collaborative transaction, are skipped.
+    /// Returns whether every input is now finalized. On error `psbt` is left unchanged.
+    pub fn finalize_psbt(&self, psbt: &mut Psbt) -> Result<bool, SigningError> {
+        // finalize a copy, so that a failing input doesn't leave the others finalized
+        let mut finalizing = psbt.clone();
+        let mut finalized = true;
+        for index in 0..finalizing.inputs.len() {
+            let input = &finalizing.inputs[index];
+            if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+                continue;
+            }
+
+            let prevout = finalizing.unsigned_tx.input[index].previous_output;
+            let spk = input
+                .witness_utxo
+                .as_ref()
+                .or_else(|| {
+                    input
+                        .non_witness_utxo
+                        .as_ref()?
+                        .output
+                        .get(prevout.vout as usize)
+                })
+                .map(|txout| txout.script_pubkey.clone());
+
+            match spk.and_then(|spk| self.derivation_of_spk(&spk)) {
+                Some((keychain, derivation_index)) => {
+                    let descriptor = self
+                        .keyring
+                        .get_descriptor(&keychain)
+                        .ok_or(SigningError::KeychainNotFound)?
+                        .at_derivation_index(derivation_index)
+                        .map_err(|_| SigningError::SigningFailed)?;
+                    finalizing
+                        .update_input_with_descriptor(index, &descriptor)
+                        .map_err(|_| SigningError::SigningFailed)?;
+                    finalizing
+                        .finalize_inp_mut(&self.keyring.secp, index)
+                        .map_err(|_| SigningError::SigningFailed)?;
+                }
+                None => {
+                    if finalizing.finalize_inp_mut(&self.keyring.secp, index).is_err() {
+                        finalized = false;
+                    }
+                }
+            }
+        }
+        *psbt = finalizing;
+        Ok(finalized)
+    }
+
+    /// Finalize `psbt` like [`finalize_psbt`](Self::finalize_psbt), but satisfy the wallet's
+    /// descriptors with `satisfier` in addition to the signatures and preimages in the PSBT.
+    ///
+    /// This finalizes descriptors the PSBT alone can't satisfy, e.g. HTLCs given the preimage of
+    /// a hash or a timelock known to have passed. Foreign inputs are finalized from the PSBT
+    /// only. Inputs that can't be finalized are left as they are, and the outcome for each input
+    /// is reported in order.
+    pub fn finalize_psbt_with<S>(&self, psbt: &mut Psbt, satisfier: &S) -> Vec<InputFinalization>
+    where
+        S: Satisfier<DefiniteDescriptorKey>,
+    {
+        (0..psbt.inputs.len())
+            .map(|index| match self.finalize_input_with(psbt, index, satisfier) {
+                Ok(finalization) => finalization,
+                Err(reason) => InputFinalization::Failed(reason),
+            })
+            .collect()
+    }
+
+    /// Finalize the input at `index` of `psbt`, see [`finalize_psbt_with`].
+    ///
+    /// [`finalize_psbt_with`]: Self::finalize_psbt_with
+    fn finalize_input_with<S>(
+        &self,
+        psbt: &mut Psbt,
+        index: usize,
+        satisfier: &S,
+    ) -> Result<InputFinalization, String>
+    where
+        S: Satisfier<DefiniteDescriptorKey>,
+    {
+        let input = &psbt.inputs[index];
+        if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+            return Ok(InputFinalization::AlreadyFinalized);
+        }
+
+        let prevout = psbt.unsigned_tx.input[index].previous_output;
+        let derivation = input
+            .witness_utxo
+            .as_ref()
+            .or_else(|| {
+                input
+                    .non_witness_utxo
+                    .as_ref()?
+                    .output
+                    .get(prevout.vout as usize)
+            })
+            .and_then(|txout| self.derivation_of_spk(&txout.script_pubkey));
+        let (keychain, derivation_index) = match derivation {
+            Some(derivation) => derivation,
+            None => {
+                psbt.finalize_inp_mut(&self.keyring.secp, index)
+                    .map_err(|e| e.to_string())?;
+                return Ok(InputFinalization::Finalized);
+            }
+        };
+
+        let descriptor = self
+            .keyring
+            .get_descriptor(&keychain)
+            .ok_or_else(|| "keychain not found".to_string())?
+            .at_derivation_index(derivation_index)
+            .map_err(|e| e.to_string())?;
+        psbt.update_input_with_descriptor(index, &descriptor)
+            .map_err(|e| e.to_string())?;
+        let (witness, script_sig) = descriptor
+            .get_satisfaction((PsbtInputSatisfier::new(psbt, index), satisfier))
+            .map_err(|e| e.to_string())?;
+
+        // Like any finalizer, keep only the UTXO, the final fields and unknown data
+        let input = &mut psbt.inputs[index];
+        *input = bitcoin::psbt::Input {
+            non_witness_utxo: input.non_witness_utxo.take(),
+            witness_utxo: input.witness_utxo.take(),
+            final_script_sig: Some(script_sig).filter(|script_sig| !script_sig.is_empty()),
+            final_script_witness: Some(Witness::from_slice(&witness))
+                .filter(|witness| !witness.is_empty()),
+            proprietary: core::mem::take(&mut input.proprietary),
+            unknown: core::mem::take(&mut input.unknown),
+            ..Default::default()
+        };
+        Ok(InputFinalization::Finalized)
+    }
+
+    /// Set the [`CanonicalizationParams`] used to resolve conflicts between transactions when
+    /// computing balances, history and spendable UTXOs.
+    ///
+    /// For example, list a replacement of our own in `assume_canonical` so it wins over the
+    /// transaction it replaces.
+    pub fn set_canonicalization_params(&mut self, params: CanonicalizationParams) {
+        self.canonicalization_params = params;
+    }
+
+    /// Get the [`CanonicalizationParams`] used by the wallet.
+    pub fn canonicalization_params(&self) -> &CanonicalizationParams {
+        &self.canonicalization_params
+    }
+
+    /// Combine the partial signatures of `others` into `base`, e.g. PSBTs returned by the
+    /// cosigners of a multisig.
+    ///
+    /// Fails with [`SigningError::InvalidSignature`] if any of the PSBTs spends a different
+    /// unsigned transaction, in which case `base` is left untouched.
+    pub fn combine_psbts(
+        &self,
+        base: &mut Psbt,
+        others: impl IntoIterator<Item = Psbt>,
+    ) -> Result<(), SigningError> {
+        let others: Vec<Psbt> = others.into_iter().collect();
+        if others
+            .iter()
+            .any(|other| other.unsigned_tx != base.unsigned_tx)
+        {
+            return Err(SigningError::InvalidSignature);
+        }
+        for other in others {
+            base.combine(other)
+                .map_err(|_| SigningError::InvalidSignature)?;
+        }
+        Ok(())
+    }
+
+    /// Set the maximum absolute fee [`extract_tx`](Self::extract_tx) accepts.
+    ///
+    /// Defaults to [`DEFAULT_MAX_FEE`].
+    pub fn set_max_fee(&mut self, max_fee: Amount) {
+        self.max_fee = max_fee;
+    }
+
+    /// Extract the transaction from a finalized `psbt`, ready to broadcast.
+    ///
+    /// As a guard against fat-fingered fees, this fails with [`TxBuilderError::FeeTooHigh`] if
+    /// the transaction pays more than the [maximum fee](Self::set_max_fee) or an absurd fee rate.
+    /// Fails with [`SigningError::SigningFailed`] if any input is not finalized.
+    pub fn extract_tx(&self, psbt: Psbt) -> Result<Transaction, WalletError> {
+        let all_finalized = psbt.inputs.iter().all(|input| {
+            input.final_script_sig.is_some() || input.final_script_witness.is_some()
+        });
+        if !all_finalized {
+            return Err(SigningError::SigningFailed.into());
+        }
+
+        let fee = psbt.fee().map_err(|_| SigningError::InputNotFound)?;
+        if fee > self.max_fee {
+            return Err(TxBuilderError::FeeTooHigh.into());
+        }
+
+        psbt.extract_tx().map_err(|err| match err {
+            ExtractTxError::AbsurdFeeRate { .. } => TxBuilderError::FeeTooHigh.into(),
+            ExtractTxError::MissingInputValue { .. } => SigningError::InputNotFound.into(),
+            _ => SigningError::SigningFailed.into(),
+        })
+    }
+
+    /// Weight of a transaction input spending an output of `keychain`, assuming the worst-case
+    /// satisfaction of its descriptor.
+    ///
+    /// Returns `None` if the keychain is unknown or its descriptor can't be satisfied.
+    ///
+    /// A `tr()` descriptor without a script tree is weighed as a key path spend with a single
+    /// 64-byte Schnorr signature, since the default sighash type needs no sighash byte. With a
+    /// script tree the most expensive script path is assumed, including the leaf script and
+    /// control block.
+    pub fn input_weight(&self, keychain: &K) -> Option<Weight> {
+        let descriptor = self.keyring.get_descriptor(keychain)?;
+        let satisfaction_weight = match descriptor {
+            // signature length and signature, the empty witness of the default input already
+            // counts the witness item count
+            Descriptor::Tr(tr) if tr.tap_tree().is_none() => Weight::from_wu(1 + 64),
+            descriptor => descriptor.max_weight_to_satisfy().ok()?,
+        };
+        Some(TxIn::default().segwit_weight() + satisfaction_weight)
+    }
+
+    /// Total weight of the inputs needed to spend every spendable UTXO in the wallet.
+    ///
+    /// This is useful to estimate the cost of consolidating all funds into a single output.
+    pub fn total_spendable_weight(&self) -> Weight {
+        let mut total = Weight::ZERO;
+        for keychain in self.keyring.keychains() {
+            if let Some(input_weight) = self.input_weight(keychain) {
+                let utxo_count = self.list_unspent_for_keychain(keychain).len() as u64;
+                total += input_weight * utxo_count;
+            }
+        }
+        total
+    }
+
+    /// Iterate over the revealed addresses of every keychain, with whether each has been used.
+    ///
+    /// The script pubkeys are read from the index rather than derived again. See
+    /// [`revealed_addresses`](Self::revealed_addresses) for a single keychain.
+    pub fn all_revealed_addresses(&self) -> impl Iterator<Item = (K, u32, Address, bool)> + '_ {
+        let index = &self.tx_graph.index;
+        index
+            .revealed_spks(..)
+            .filter_map(move |((keychain, i), spk)| {
+                let address = Address::from_script(&spk, self.keyring.network).ok()?;
+                let used = index.is_used(keychain.clone(), i);
+                Some((keychain, i, address, used))
+            })
+    }
+
     /// Get all revealed addresses for a keychain
     pub fn revealed_addresses(&self, keychain: &K) -> Vec<(u32, Address)> {
         let mut addresses = Vec::new();
@@ -302,37 +1753,103 @@ where
         addresses
     }
 
+    /// Get all unspent outputs of the wallet.
+    pub fn list_unspent(&self) -> Vec<LocalUtxo<K>> {
+        self.tx_graph
+            .graph()
+            .filter_chain_unspents(
+                &self.chain,
+                self.chain.tip().block_id(),
+                self.canonicalization_params.clone(),
+                self.tx_graph.index.outpoints().iter().cloned(),
+            )
+            .map(|((keychain, derivation_index), full_txout)| LocalUtxo {
+                outpoint: full_txout.outpoint,
+                address: Address::from_script(&full_txout.txout.script_pubkey, self.keyring.network)
+                    .ok(),
+                txout: full_txout.txout,
+                keychain,
+                derivation_index,
+                chain_position: full_txout.chain_position,
+            })
+            .collect()
+    }
+
     /// Get all unspent outputs for a specific keychain
     pub fn list_unspent_for_keychain(&self, keychain: &K) -> Vec<LocalUtxo<K>> {
-        let chain = &self.chain;
-        let tip = chain.tip().block_id();
-        let mut utxos = Vec::new();
-
-        for ((k, index), outpoint) in self.tx_graph.index.outpoints() {
-            if k == keychain {
-                if let Some(tx_node) = self.tx_graph.graph().get_tx_node(outpoint.txid) {
-                    if let Some(txout) = tx_node.tx.output.get(outpoint.vout as usize) {
-                        let is_unspent = self.tx_graph.graph()
-                            .filter_chain_unspents(chain, tip, CanonicalizationParams::default(), [((), *outpoint)].iter().cloned())
-                            .next()
-                            .is_some();
-
-                        if is_unspent {
-                            utxos.push(LocalUtxo {
-                                outpoint: *outpoint,
-                                txout: txout.clone(),
-                                keychain: k.clone(),
-                                derivation_index: *index,
-                            });
-                        }
-                    }
-                }
-            }
-        }
+        self.list_unspent()
+            .into_iter()
+            .filter(|utxo| &utxo.keychain == keychain)
+            .collect()
+    }
+
+}
+
+/// How far the wallet is synced, see [`Wallet::sync_status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncStatus {
+    /// Height of the local chain tip.
+    pub tip_height: u32,
+    /// Hash of the local chain tip.
+    pub tip_hash: BlockHash,
+    /// Most recent time an unconfirmed transaction was seen in the mempool, if any.
+    pub latest_seen_unconfirmed: Option<u64>,
+}
+
+/// Outcome of finalizing a PSBT input, see [`Wallet::finalize_psbt_with`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputFinalization {
+    /// The input was finalized.
+    Finalized,
+    /// The input was already finalized and was left as it is.
+    AlreadyFinalized,
+    /// The input couldn't be finalized, for the given reason.
+    Failed(String),
+}
+
+/// What a PSBT does, see [`Wallet::describe_psbt`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PsbtSummary<K> {
+    /// Each output of the transaction with whom it pays.
+    pub outputs: Vec<(TxOut, OutputKind<K>)>,
+    /// Fee paid by the transaction.
+    pub fee: Amount,
+    /// Total value of the wallet's outputs spent.
+    pub sent: Amount,
+    /// Total value of the outputs paying the wallet.
+    pub received: Amount,
+}
 
-        utxos
+impl<K> PsbtSummary<K> {
+    /// Net amount leaving the wallet, including the fee if the wallet pays it.
+    pub fn net_sent(&self) -> SignedAmount {
+        // Both are at most the total supply of bitcoin, so they fit in an i64
+        SignedAmount::from_sat(self.sent.to_sat() as i64 - self.received.to_sat() as i64)
     }
+}
 
+/// Whom an output of a PSBT pays, see [`PsbtSummary`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputKind<K> {
+    /// Change back to the wallet, at this keychain and derivation index.
+    Change(K, u32),
+    /// A payment to the wallet, at this keychain and derivation index.
+    Mine(K, u32),
+    /// A payment to someone else.
+    External,
+}
+
+/// UTXO and address usage statistics of a keychain, see [`Wallet::keychain_stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeychainStats {
+    /// Number of unspent outputs.
+    pub utxo_count: usize,
+    /// Total value of the unspent outputs.
+    pub total_value: Amount,
+    /// Index of the last revealed script pubkey, if any.
+    pub last_revealed_index: Option<u32>,
+    /// Number of revealed script pubkeys that have been used.
+    pub used_count: usize,
 }
 
 #[cfg(feature = "rusqlite")]
@@ -344,12 +1861,7 @@ use crate::multi_keychain::tx_builder::LocalUtxo;
 impl Wallet<DescriptorId> {
     /// Construct [`Wallet`] from SQLite.
     pub fn from_sqlite(conn: &mut rusqlite::Connection) -> rusqlite::Result<Option<Self>> {
-        let tx = conn.transaction()?;
-
-        let changeset = ChangeSet::initialize(&tx)?;
-        tx.commit()?;
-
-        Ok(changeset.and_then(Self::from_changeset))
+        Self::load(conn)
     }
 
     /// Persist to SQLite. Returns the newly committed changeset if successful, or `None`
@@ -371,6 +1883,54 @@ impl Wallet<DescriptorId> {
         Ok(ret)
     }
 
+    /// Persist to SQLite like [`persist_to_sqlite`](Self::persist_to_sqlite), but commit the
+    /// staged transactions in batches of `batch_size`, each in its own database transaction,
+    /// before committing the rest of the stage.
+    ///
+    /// This keeps every write transaction small when persisting a wallet with many transactions,
+    /// e.g. after a full scan. Each batch is taken out of the stage once it is committed. If a
+    /// batch fails, the batches before it stay written and everything else stays staged, so
+    /// calling this again picks up where it left off. Writing transactions ahead of the rest is
+    /// safe as the tx graph only ever grows.
+    ///
+    /// Returns whether there was anything to persist.
+    pub fn persist_to_sqlite_chunked(
+        &mut self,
+        conn: &mut rusqlite::Connection,
+        batch_size: usize,
+    ) -> rusqlite::Result<bool> {
+        if self.stage.is_empty() {
+            return Ok(false);
+        }
+
+        while !self.stage.tx_graph.txs.is_empty() {
+            let batch: crate::collections::BTreeSet<Arc<Transaction>> = self
+                .stage
+                .tx_graph
+                .txs
+                .iter()
+                .take(batch_size.max(1))
+                .cloned()
+                .collect();
+            let db_tx = conn.transaction()?;
+            bdk_chain::tx_graph::ChangeSet::<ConfirmationBlockTime> {
+                txs: batch.clone(),
+                ..Default::default()
+            }
+            .persist_to_sqlite(&db_tx)?;
+            db_tx.commit()?;
+            for tx in &batch {
+                self.stage.tx_graph.txs.remove(tx);
+            }
+        }
+
+        let db_tx = conn.transaction()?;
+        self.stage.persist_to_sqlite(&db_tx)?;
+        db_tx.commit()?;
+        self.stage = ChangeSet::default();
+        Ok(true)
+    }
+
     /// See the staged changes if any.
     pub fn staged_changeset(&self) -> Option<&ChangeSet<DescriptorId>> {
         if self.stage.is_empty() {
@@ -379,6 +1939,87 @@ impl Wallet<DescriptorId> {
             Some(&self.stage)
         }
     }
+
+    /// Wrap the wallet in a [`PersistOnDrop`] guard which persists any staged changes to `conn`
+    /// when it goes out of scope.
+    #[cfg(feature = "std")]
+    pub fn auto_persist_on_drop(self, conn: &mut rusqlite::Connection) -> PersistOnDrop<'_> {
+        PersistOnDrop {
+            wallet: self,
+            conn,
+            on_error: None,
+        }
+    }
+}
+
+/// Guard which owns a [`Wallet`] and persists its staged changes to SQLite when dropped.
+///
+/// The guard dereferences to the inner [`Wallet`].
+///
+/// # Lost changes
+///
+/// Since `drop` can't return an error, a failure to persist on drop doesn't panic, and the
+/// staged changes are lost with the wallet. Register a handler with
+/// [`on_error`](Self::on_error) to observe the failure, e.g. to log it, or call
+/// [`persist`](Self::persist) to handle the error instead, after which nothing is left for the
+/// drop to persist.
+#[cfg(all(feature = "rusqlite", feature = "std"))]
+pub struct PersistOnDrop<'a> {
+    wallet: Wallet<DescriptorId>,
+    conn: &'a mut rusqlite::Connection,
+    on_error: Option<alloc::boxed::Box<dyn FnMut(rusqlite::Error) + 'a>>,
+}
+
+#[cfg(all(feature = "rusqlite", feature = "std"))]
+impl<'a> PersistOnDrop<'a> {
+    /// Persist the staged changes now, see [`Wallet::persist_to_sqlite`].
+    pub fn persist(&mut self) -> rusqlite::Result<Option<ChangeSet<DescriptorId>>> {
+        self.wallet.persist_to_sqlite(self.conn)
+    }
+
+    /// Call `on_error` with the error if persisting on drop fails.
+    pub fn on_error(mut self, on_error: impl FnMut(rusqlite::Error) + 'a) -> Self {
+        self.on_error = Some(alloc::boxed::Box::new(on_error));
+        self
+    }
+}
+
+#[cfg(all(feature = "rusqlite", feature = "std"))]
+impl fmt::Debug for PersistOnDrop<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PersistOnDrop")
+            .field("wallet", &self.wallet)
+            .field("conn", &self.conn)
+            .field("on_error", &self.on_error.is_some())
+            .finish()
+    }
+}
+
+#[cfg(all(feature = "rusqlite", feature = "std"))]
+impl core::ops::Deref for PersistOnDrop<'_> {
+    type Target = Wallet<DescriptorId>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.wallet
+    }
+}
+
+#[cfg(all(feature = "rusqlite", feature = "std"))]
+impl core::ops::DerefMut for PersistOnDrop<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.wallet
+    }
+}
+
+#[cfg(all(feature = "rusqlite", feature = "std"))]
+impl Drop for PersistOnDrop<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = self.wallet.persist_to_sqlite(self.conn) {
+            if let Some(on_error) = &mut self.on_error {
+                on_error(err);
+            }
+        }
+    }
 }
 
 /// Contains structures for updating a multi-keychain wallet.
@@ -392,6 +2033,27 @@ pub struct Update<K> {
     pub last_active_indices: BTreeMap<K, u32>,
 }
 
+impl<K> Update<K> {
+    /// Construct an [`Update`] from its components.
+    pub fn new(
+        chain: Option<CheckPoint>,
+        tx_update: bdk_chain::TxUpdate<ConfirmationBlockTime>,
+        last_active_indices: BTreeMap<K, u32>,
+    ) -> Self {
+        Self {
+            chain,
+            tx_update,
+            last_active_indices,
+        }
+    }
+
+    /// Construct an [`Update`] carrying only transaction data, without a chain update or
+    /// keychain indices.
+    pub fn from_tx_update(tx_update: bdk_chain::TxUpdate<ConfirmationBlockTime>) -> Self {
+        Self::new(None, tx_update, BTreeMap::new())
+    }
+}
+
 impl<K> From<bdk_chain::spk_client::FullScanResponse<K>> for Update<K> {
     fn from(resp: bdk_chain::spk_client::FullScanResponse<K>) -> Self {
         Self {
@@ -401,3 +2063,772 @@ impl<K> From<bdk_chain::spk_client::FullScanResponse<K>> for Update<K> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::multi_keychain::test_utils::*;
+    use bitcoin::hashes::Hash;
+
+    #[test]
+    fn apply_unconfirmed_txs_counts_pending_receive() {
+        let mut wallet = new_wallet();
+        let outpoint = receive_unconfirmed(
+            &mut wallet,
+            Keychain::External,
+            Amount::from_sat(10_000),
+            100,
+        );
+
+        let balance = wallet.balance();
+        assert_eq!(balance.untrusted_pending, Amount::from_sat(10_000));
+        assert_eq!(balance.confirmed, Amount::ZERO);
+
+        let staged = wallet.staged().expect("changes are staged");
+        assert!(staged.tx_graph.txs.iter().any(|tx| tx.compute_txid() == outpoint.txid));
+        assert!(staged.tx_graph.last_seen.contains_key(&outpoint.txid));
+    }
+
+    #[test]
+    fn apply_unconfirmed_txs_reflects_pending_spend() {
+        let mut wallet = new_wallet();
+        let outpoint =
+            receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(50_000), 1);
+        assert_eq!(wallet.balance().confirmed, Amount::from_sat(50_000));
+
+        let tx = spending_tx(&[outpoint], external_spk(), Amount::from_sat(49_000));
+        wallet.apply_unconfirmed_txs([(Arc::new(tx), 200)]);
+
+        assert_eq!(wallet.balance().total(), Amount::ZERO);
+        assert!(wallet.list_unspent().is_empty());
+    }
+
+    #[test]
+    fn insert_checkpoint_reorg_unconfirms_txs_above() {
+        let mut wallet = new_wallet();
+        for height in 1..=3 {
+            insert_checkpoint(&mut wallet, height);
+        }
+        let outpoint =
+            receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(20_000), 3);
+        assert_eq!(wallet.balance().confirmed, Amount::from_sat(20_000));
+
+        // a competing block at height 2 invalidates blocks 2 and 3
+        let reorged = BlockId {
+            height: 2,
+            hash: BlockHash::hash(b"reorg"),
+        };
+        wallet.insert_checkpoint(reorged).unwrap();
+
+        assert_eq!(wallet.local_chain().tip().block_id(), reorged);
+        assert!(wallet.local_chain().get(3).is_none());
+
+        // the transaction is no longer confirmed, so it only counts as pending
+        let balance = wallet.balance();
+        assert_eq!(balance.confirmed, Amount::ZERO);
+        assert_eq!(balance.untrusted_pending, Amount::from_sat(20_000));
+        assert!(matches!(
+            wallet.tx_chain_position(outpoint.txid),
+            Some(ChainPosition::Unconfirmed { .. })
+        ));
+        assert!(wallet
+            .list_unspent()
+            .iter()
+            .all(|utxo| !utxo.chain_position.is_confirmed()));
+        assert_eq!(wallet.confirmations(outpoint), None);
+    }
+
+    #[test]
+    fn insert_checkpoint_below_tip_in_sparse_chain_invalidates_above() {
+        let mut wallet = new_wallet();
+        for height in [1, 5, 10] {
+            insert_checkpoint(&mut wallet, height);
+        }
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(20_000), 10);
+
+        // the chain has no block at height 7, but blocks 10 and above are now stale
+        let reorged = BlockId {
+            height: 7,
+            hash: BlockHash::hash(b"reorg"),
+        };
+        wallet.insert_checkpoint(reorged).unwrap();
+
+        assert_eq!(wallet.local_chain().tip().block_id(), reorged);
+        assert!(wallet.local_chain().get(10).is_none());
+        assert_eq!(wallet.local_chain().get(5).map(|cp| cp.block_id()), Some(block_id(5)));
+        assert_eq!(wallet.balance().confirmed, Amount::ZERO);
+    }
+
+    #[test]
+    fn insert_checkpoint_cannot_replace_genesis() {
+        let mut wallet = new_wallet();
+        let genesis = BlockId {
+            height: 0,
+            hash: BlockHash::hash(b"not genesis"),
+        };
+        assert!(matches!(
+            wallet.insert_checkpoint(genesis),
+            Err(WalletError::Chain(ChainError::Reorg { height: 0 }))
+        ));
+        assert_eq!(wallet.genesis_hash(), block_id(0).hash);
+    }
+
+    #[test]
+    fn insert_checkpoint_is_idempotent() {
+        let mut wallet = new_wallet();
+        insert_checkpoint(&mut wallet, 1);
+        wallet.take_staged();
+
+        wallet.insert_checkpoint(block_id(1)).unwrap();
+        assert!(wallet.staged().is_none());
+    }
+
+    #[test]
+    fn tx_confirmation_likely_compares_feerate() {
+        let mut wallet = new_wallet();
+        let outpoint =
+            receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(50_000), 1);
+        // pays a 100 sat fee, about 1.2 sat/vB
+        let tx = spending_tx(&[outpoint], external_spk(), Amount::from_sat(49_900));
+        let txid = wallet.record_broadcast(tx, 100);
+
+        let low = FeeRate::from_sat_per_vb(1).unwrap();
+        let high = FeeRate::from_sat_per_vb(10).unwrap();
+        assert!(wallet.tx_confirmation_likely(txid, low));
+        assert!(!wallet.tx_confirmation_likely(txid, high));
+
+        // unknown fee rate
+        assert!(!wallet.tx_confirmation_likely(Txid::all_zeros(), low));
+    }
+
+    #[cfg(all(feature = "rusqlite", feature = "std"))]
+    #[test]
+    fn persist_on_drop_persists_staged_changes() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        assert!(Wallet::from_sqlite(&mut conn).unwrap().is_none());
+        let mut keyring = KeyRing::new(bitcoin::Network::Signet);
+        let (receive, _) = keyring.add_multipath_descriptor(WPKH_MULTIPATH);
+        {
+            let mut wallet = Wallet::new(keyring).auto_persist_on_drop(&mut conn);
+            wallet.reveal_next_address(receive).unwrap();
+            wallet.reveal_next_address(receive).unwrap();
+        }
+
+        let wallet = Wallet::from_sqlite(&mut conn).unwrap().expect("wallet was persisted");
+        assert_eq!(wallet.index().last_revealed_index(receive), Some(1));
+    }
+
+    #[cfg(all(feature = "rusqlite", feature = "std"))]
+    #[test]
+    fn persist_on_drop_explicit_persist() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        assert!(Wallet::from_sqlite(&mut conn).unwrap().is_none());
+        let mut keyring = KeyRing::new(bitcoin::Network::Signet);
+        let (receive, _) = keyring.add_multipath_descriptor(WPKH_MULTIPATH);
+        let mut wallet = Wallet::new(keyring).auto_persist_on_drop(&mut conn);
+        wallet.reveal_next_address(receive).unwrap();
+
+        assert!(wallet.persist().unwrap().is_some());
+        assert!(wallet.staged().is_none());
+        assert!(wallet.persist().unwrap().is_none());
+    }
+
+    #[cfg(all(feature = "rusqlite", feature = "std"))]
+    #[test]
+    fn persist_on_drop_reports_errors() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        assert!(Wallet::from_sqlite(&mut conn).unwrap().is_none());
+        conn.execute_batch("PRAGMA query_only = ON").unwrap();
+        let mut keyring = KeyRing::new(bitcoin::Network::Signet);
+        let (receive, _) = keyring.add_multipath_descriptor(WPKH_MULTIPATH);
+        let mut errors = Vec::new();
+        {
+            let mut wallet = Wallet::new(keyring)
+                .auto_persist_on_drop(&mut conn)
+                .on_error(|err| errors.push(err));
+            wallet.reveal_next_address(receive).unwrap();
+        }
+        assert_eq!(errors.len(), 1);
+
+        conn.execute_batch("PRAGMA query_only = OFF").unwrap();
+        assert!(Wallet::from_sqlite(&mut conn).unwrap().is_none());
+    }
+
+    #[cfg(feature = "rusqlite")]
+    #[test]
+    fn persist_chunked_leaves_unwritten_batches_staged() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        assert!(Wallet::from_sqlite(&mut conn).unwrap().is_none());
+        let mut keyring = KeyRing::new(bitcoin::Network::Signet);
+        let (receive, _) = keyring.add_multipath_descriptor(WPKH_MULTIPATH);
+        let mut wallet = Wallet::new(keyring);
+        for sats in 1..=5 {
+            receive_unconfirmed(&mut wallet, receive, Amount::from_sat(sats * 10_000), sats);
+        }
+
+        // Fail any insert once two transactions are written, i.e. in the second batch
+        conn.execute_batch(
+            "CREATE TEMP TRIGGER fail_third_tx BEFORE INSERT ON bdk_txs \
+             WHEN (SELECT COUNT(*) FROM bdk_txs) >= 2 \
+             BEGIN SELECT RAISE(ABORT, 'disk full'); END",
+        )
+        .unwrap();
+        assert!(wallet.persist_to_sqlite_chunked(&mut conn, 2).is_err());
+
+        let count_txs = |conn: &rusqlite::Connection| -> u32 {
+            conn.query_row("SELECT COUNT(*) FROM bdk_txs", [], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(count_txs(&conn), 2);
+        let staged = wallet.staged().unwrap();
+        assert_eq!(staged.tx_graph.txs.len(), 3);
+        assert!(!staged.keyring.is_empty());
+
+        conn.execute_batch("DROP TRIGGER fail_third_tx").unwrap();
+        assert!(wallet.persist_to_sqlite_chunked(&mut conn, 2).unwrap());
+        assert!(wallet.staged().is_none());
+        assert!(!wallet.persist_to_sqlite_chunked(&mut conn, 2).unwrap());
+        assert_eq!(count_txs(&conn), 5);
+
+        let loaded = Wallet::from_sqlite(&mut conn).unwrap().unwrap();
+        assert_eq!(loaded.transactions().count(), 5);
+        assert_eq!(loaded.balance(), wallet.balance());
+    }
+
+    #[test]
+    fn keychains_match_keyring() {
+        let wallet = new_wallet();
+        let keychains: Vec<_> = wallet.keychains().collect();
+        assert_eq!(keychains.len(), 2);
+        assert_eq!(keychains[0].0, Keychain::External);
+        assert_eq!(keychains[1].0, Keychain::Internal);
+        for (keychain, descriptor) in keychains {
+            assert_eq!(Some(descriptor), wallet.keyring.get_descriptor(&keychain));
+        }
+    }
+
+    /// Expected address of `tr(<internal>/0/*, pk(<leaf>/0/*))` at `index`, built without
+    /// miniscript.
+    fn tr_script_tree_address(internal: &str, leaf: &str, index: u32) -> Address {
+        use bitcoin::bip32::{ChildNumber, Xpub};
+        use bitcoin::taproot::TaprootBuilder;
+        use core::str::FromStr;
+
+        let secp = bitcoin::secp256k1::Secp256k1::verification_only();
+        let path = [
+            ChildNumber::from_normal_idx(0).unwrap(),
+            ChildNumber::from_normal_idx(index).unwrap(),
+        ];
+        let derive = |xpub: &str| {
+            Xpub::from_str(xpub)
+                .unwrap()
+                .derive_pub(&secp, &path)
+                .unwrap()
+                .to_x_only_pub()
+        };
+        let leaf_script = bitcoin::script::Builder::new()
+            .push_x_only_key(&derive(leaf))
+            .push_opcode(bitcoin::opcodes::all::OP_CHECKSIG)
+            .into_script();
+        let spend_info = TaprootBuilder::new()
+            .add_leaf(0, leaf_script)
+            .unwrap()
+            .finalize(&secp, derive(internal))
+            .unwrap();
+        Address::p2tr_tweaked(spend_info.output_key(), bitcoin::KnownHrp::Testnets)
+    }
+
+    #[test]
+    fn reveal_next_address_commits_to_tr_script_tree() {
+        const INTERNAL: &str = "tpubD6NzVbkrYhZ4WaWSyoBvQwbpLkojyoTZPRsgXELWz3Popb3qkjcJyJUGLnL4qHHoQvao8ESaAstxYSnhyswJ76uZPStJRJCTKvosUCJZL5B";
+        const LEAF: &str = "tpubDDjbeNLuVodUZaDX4M6gG5rV1RmNBkTQmaotRzEBaj8A7WwYXLqzfs7V5WjtuiyteqjpvzZ348xDYGFJ6YSSvipP3Dj26DQXp6ZH6L4G2gW";
+        let descriptor = alloc::format!("tr({}/0/*,pk({}/0/*))", INTERNAL, LEAF);
+        let mut wallet = new_wallet_with(&[(Keychain::External, &descriptor)]);
+
+        for expected_index in 0..2 {
+            let ((_, index), address) = wallet.reveal_next_address(Keychain::External).unwrap();
+            assert_eq!(index, expected_index);
+            assert_eq!(address, tr_script_tree_address(INTERNAL, LEAF, index));
+
+            // the descriptor was inserted into the index, so the wallet recognizes the output
+            let spk = address.script_pubkey();
+            assert!(wallet.is_mine(&spk));
+            assert_eq!(
+                wallet.derivation_of_spk(&spk),
+                Some((Keychain::External, index))
+            );
+        }
+
+        // the output key differs from the key path only descriptor with the same internal key
+        let mut key_path_only =
+            new_wallet_with(&[(Keychain::External, &alloc::format!("tr({}/0/*)", INTERNAL))]);
+        let (_, key_path_address) = key_path_only
+            .reveal_next_address(Keychain::External)
+            .unwrap();
+        assert_ne!(key_path_address, tr_script_tree_address(INTERNAL, LEAF, 0));
+    }
+
+    #[cfg(feature = "rusqlite")]
+    #[test]
+    fn used_marks_are_persisted() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        assert!(Wallet::from_sqlite(&mut conn).unwrap().is_none());
+        let mut keyring = KeyRing::new(bitcoin::Network::Signet);
+        let (receive, _) = keyring.add_multipath_descriptor(WPKH_MULTIPATH);
+        let mut wallet = Wallet::new(keyring);
+        for _ in 0..3 {
+            wallet.reveal_next_address(receive).unwrap();
+        }
+        assert!(wallet.mark_used(receive, 0));
+        assert!(wallet.mark_used(receive, 1));
+        assert!(!wallet.mark_used(receive, 1));
+        assert!(wallet.unmark_used(receive, 1));
+        assert_eq!(
+            wallet.staged().unwrap().used_marks[&receive],
+            BTreeMap::from([(0, true), (1, false)])
+        );
+        wallet.persist_to_sqlite(&mut conn).unwrap();
+
+        let mut wallet = Wallet::from_sqlite(&mut conn).unwrap().unwrap();
+        let ((_, index), _) = wallet.next_unused_address(receive).unwrap();
+        assert_eq!(index, 1);
+
+        // undoing a persisted mark replaces it
+        assert!(wallet.unmark_used(receive, 0));
+        wallet.persist_to_sqlite(&mut conn).unwrap();
+        let mut wallet = Wallet::from_sqlite(&mut conn).unwrap().unwrap();
+        let ((_, index), _) = wallet.next_unused_address(receive).unwrap();
+        assert_eq!(index, 0);
+    }
+
+    #[test]
+    fn max_reveal_index_caps_unused_and_psbt_addresses() {
+        let mut wallet = new_wallet();
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(100_000), 1);
+        wallet.set_max_reveal_index(Keychain::External, 3);
+        let psbt_paying = |wallet: &mut Wallet<Keychain>, index: u32| {
+            let spk = wallet.derive_spk(Keychain::External, index).unwrap();
+            // drain, as change to the external keychain would reveal an address
+            let (psbt, _) = wallet
+                .build_tx()
+                .drain_wallet()
+                .add_recipient_script(spk, Amount::ZERO)
+                .finish()
+                .unwrap();
+            psbt
+        };
+
+        let psbt = psbt_paying(&mut wallet, 5);
+        assert_eq!(
+            wallet.register_psbt_outputs(&psbt),
+            Err(AddressGenerationError::DerivationLimit)
+        );
+        assert_eq!(wallet.index().last_revealed_index(Keychain::External), Some(0));
+        let psbt = psbt_paying(&mut wallet, 3);
+        wallet.register_psbt_outputs(&psbt).unwrap();
+        assert_eq!(wallet.index().last_revealed_index(Keychain::External), Some(3));
+
+        let ((_, index), _) = wallet.try_next_unused_address(Keychain::External).unwrap();
+        assert_eq!(index, 1);
+        for index in 1..=3 {
+            wallet.mark_used(Keychain::External, index);
+        }
+        assert_eq!(
+            wallet.try_next_unused_address(Keychain::External),
+            Err(AddressGenerationError::DerivationLimit)
+        );
+        assert_eq!(wallet.next_unused_address(Keychain::External), None);
+        assert_eq!(wallet.index().last_revealed_index(Keychain::External), Some(3));
+    }
+
+    #[test]
+    fn non_ranged_keychain_only_has_index_0() {
+        const SINGLE_KEY: &str = "wpkh(cVt4o7BGAig1UXywgGSmARhxMdzP5qvQsxKkSsc1XEkw3tDTQFpy)";
+        let mut wallet = new_wallet_with(&[(Keychain::External, SINGLE_KEY)]);
+        assert_eq!(wallet.keyring().is_ranged(&Keychain::External), Some(false));
+
+        let ((_, index), peeked) = wallet.peek_next_address(Keychain::External).unwrap();
+        assert_eq!(index, 0);
+        let ((_, index), address) = wallet.reveal_next_address(Keychain::External).unwrap();
+        assert_eq!(index, 0);
+        assert_eq!(address, peeked);
+
+        for _ in 0..2 {
+            let ((_, index), next) = wallet.reveal_next_address(Keychain::External).unwrap();
+            assert_eq!(index, 0);
+            assert_eq!(next, address);
+            let ((_, index), next) = wallet.peek_next_address(Keychain::External).unwrap();
+            assert_eq!(index, 0);
+            assert_eq!(next, address);
+        }
+        assert_eq!(wallet.peek_change_address(Keychain::External), Some(address.clone()));
+        assert_eq!(wallet.index().last_revealed_index(Keychain::External), Some(0));
+        assert_eq!(
+            wallet.derive_spk(Keychain::External, 0),
+            Some(address.script_pubkey())
+        );
+        assert_eq!(wallet.derive_spk(Keychain::External, 1), None);
+    }
+
+    #[test]
+    fn total_spendable_weight_sums_input_weights() {
+        let mut wallet = new_wallet_with(&[
+            (Keychain::External, WPKH_RECEIVE),
+            (Keychain::Internal, TR_RECEIVE),
+        ]);
+        assert_eq!(wallet.total_spendable_weight(), Weight::ZERO);
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(10_000), 1);
+        receive_confirmed(&mut wallet, Keychain::Internal, Amount::from_sat(10_000), 2);
+
+        let wpkh = wallet.keyring().get_descriptor(&Keychain::External).unwrap();
+        let wpkh_weight = TxIn::default().segwit_weight() + wpkh.max_weight_to_satisfy().unwrap();
+        // key path spend: a 64-byte signature and its length
+        let tr_weight = TxIn::default().segwit_weight() + Weight::from_wu(65);
+        assert_eq!(wallet.input_weight(&Keychain::External), Some(wpkh_weight));
+        assert_eq!(wallet.input_weight(&Keychain::Internal), Some(tr_weight));
+        assert_eq!(wallet.total_spendable_weight(), wpkh_weight + tr_weight);
+    }
+
+    #[test]
+    fn ensure_lookahead_indexes_scripts_past_previous_lookahead() {
+        let mut wallet = new_wallet();
+        let lookahead = wallet.index().lookahead();
+        let target = lookahead + 10;
+        assert!(wallet
+            .index()
+            .spk_at_index(Keychain::External, target + lookahead - 1)
+            .is_none());
+
+        wallet.ensure_lookahead(&BTreeMap::from([(Keychain::External, target)]));
+        assert_eq!(wallet.index().last_revealed_index(Keychain::External), Some(target));
+        let spk = wallet.derive_spk(Keychain::External, target + lookahead - 1).unwrap();
+        assert_eq!(
+            wallet.index().index_of_spk(spk),
+            Some(&(Keychain::External, target + lookahead - 1))
+        );
+        assert!(wallet.staged().is_some());
+
+        // capped at the maximum reveal index
+        wallet.set_max_reveal_index(Keychain::Internal, 5);
+        wallet.ensure_lookahead(&BTreeMap::from([(Keychain::Internal, target)]));
+        assert_eq!(wallet.index().last_revealed_index(Keychain::Internal), Some(5));
+    }
+
+    #[test]
+    fn remove_keychain_refuses_funded_keychain() {
+        let mut wallet = new_wallet();
+        receive_confirmed(&mut wallet, Keychain::Internal, Amount::from_sat(10_000), 1);
+        wallet.set_address_label(Keychain::Internal, 0, "change".into());
+        wallet.reveal_addresses_to(Keychain::External, 2).unwrap();
+        assert!(wallet.mark_used(Keychain::External, 1));
+
+        assert!(matches!(
+            wallet.remove_keychain(&Keychain::Internal),
+            Err(WalletError::KeyRing(KeyRingError::KeychainHasFunds))
+        ));
+        assert!(wallet.has_keychain(&Keychain::Internal));
+
+        wallet.force_remove_keychain(&Keychain::Internal).unwrap();
+        assert!(!wallet.has_keychain(&Keychain::Internal));
+        assert_eq!(wallet.balance().total(), Amount::ZERO);
+        assert_eq!(wallet.address_label(&Keychain::Internal, 0), None);
+        // marks on the remaining keychains survive rebuilding the index
+        assert!(wallet.index().is_used(Keychain::External, 1));
+        assert!(!wallet.index().is_used(Keychain::External, 2));
+
+        let staged = wallet.staged().unwrap();
+        assert!(staged.keyring.removed.contains(&Keychain::Internal));
+        assert!(!staged.labels.addresses.contains_key(&Keychain::Internal));
+        assert!(matches!(
+            wallet.force_remove_keychain(&Keychain::Internal),
+            Err(WalletError::KeyRing(KeyRingError::KeychainNotFound))
+        ));
+    }
+
+    #[cfg(feature = "rusqlite")]
+    #[test]
+    fn remove_keychain_is_persisted() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        assert!(Wallet::from_sqlite(&mut conn).unwrap().is_none());
+        let mut keyring = KeyRing::new(bitcoin::Network::Signet);
+        let (receive, change) = keyring.add_multipath_descriptor(WPKH_MULTIPATH);
+        let mut wallet = Wallet::new(keyring);
+        for keychain in [receive, change] {
+            wallet.reveal_next_address(keychain).unwrap();
+            wallet.set_address_label(keychain, 0, "label".into());
+            assert!(wallet.mark_used(keychain, 0));
+        }
+        wallet.persist_to_sqlite(&mut conn).unwrap();
+
+        wallet.remove_keychain(&change).unwrap();
+        wallet.persist_to_sqlite(&mut conn).unwrap();
+
+        let count_rows = |table: &str| -> u32 {
+            conn.query_row(&alloc::format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        assert_eq!(count_rows("bdk_address_label"), 1);
+        assert_eq!(count_rows("bdk_used_mark"), 1);
+
+        let wallet = Wallet::from_sqlite(&mut conn).unwrap().unwrap();
+        assert!(!wallet.has_keychain(&change));
+        assert_eq!(wallet.address_label(&change, 0), None);
+        assert_eq!(wallet.address_label(&receive, 0), Some("label"));
+        assert!(wallet.index().is_used(receive, 0));
+    }
+
+    #[test]
+    fn finalize_psbt_and_extract_tx() {
+        let mut wallet = new_wallet();
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(20_000), 1);
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(20_000), 2);
+        let (mut psbt, _) = wallet
+            .build_tx()
+            .add_recipient_script(external_spk(), Amount::from_sat(30_000))
+            .finish()
+            .unwrap();
+        assert_eq!(psbt.inputs.len(), 2);
+        assert!(matches!(
+            wallet.extract_tx(psbt.clone()),
+            Err(WalletError::Signing(SigningError::SigningFailed))
+        ));
+        assert!(wallet.sign(&mut psbt).unwrap());
+
+        // an input without signatures fails, leaving the other input unfinalized as well
+        let mut unsigned = psbt.clone();
+        unsigned.inputs[1].partial_sigs.clear();
+        let before = unsigned.clone();
+        assert_eq!(
+            wallet.finalize_psbt(&mut unsigned),
+            Err(SigningError::SigningFailed)
+        );
+        assert_eq!(unsigned, before);
+
+        // inputs finalized by someone else, e.g. a counterparty, are skipped
+        psbt.finalize_inp_mut(&wallet.keyring().secp, 0).unwrap();
+        assert!(wallet.finalize_psbt(&mut psbt).unwrap());
+
+        let mut low_max_fee = new_wallet();
+        low_max_fee.set_max_fee(Amount::from_sat(1));
+        assert!(matches!(
+            low_max_fee.extract_tx(psbt.clone()),
+            Err(WalletError::TxBuilder(TxBuilderError::FeeTooHigh))
+        ));
+        let tx = wallet.extract_tx(psbt.clone()).unwrap();
+        assert_eq!(tx.compute_txid(), psbt.unsigned_tx.compute_txid());
+        assert!(tx.input.iter().all(|txin| !txin.witness.is_empty()));
+    }
+
+    #[test]
+    fn psbt_fee_uses_psbt_and_wallet_utxos() {
+        let mut wallet = new_wallet();
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(20_000), 1);
+        let (psbt, details) = wallet
+            .build_tx()
+            .add_recipient_script(external_spk(), Amount::from_sat(10_000))
+            .finish()
+            .unwrap();
+        assert_eq!(Some(wallet.psbt_fee(&psbt).unwrap()), details.fee);
+
+        // a wallet that doesn't know the spent output only has the PSBT to go by
+        let mut missing = psbt.clone();
+        missing.inputs[0].witness_utxo = None;
+        missing.inputs[0].non_witness_utxo = None;
+        assert_eq!(Some(wallet.psbt_fee(&missing).unwrap()), details.fee);
+        let other = new_wallet();
+        assert_eq!(Some(other.psbt_fee(&psbt).unwrap()), details.fee);
+        assert_eq!(other.psbt_fee(&missing), Err(PsbtFeeError::MissingUtxo(0)));
+
+        let mut overspent = psbt;
+        overspent.unsigned_tx.output[0].value = Amount::from_sat(30_000);
+        assert_eq!(wallet.psbt_fee(&overspent), Err(PsbtFeeError::NegativeFee));
+    }
+
+    #[test]
+    fn combine_psbts_merges_signatures() {
+        let mut wallet = new_wallet();
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(20_000), 1);
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(20_000), 2);
+        let (mut signed, _) = wallet
+            .build_tx()
+            .add_recipient_script(external_spk(), Amount::from_sat(30_000))
+            .finish()
+            .unwrap();
+        assert!(wallet.sign(&mut signed).unwrap());
+
+        // each copy only has the signature of one input
+        let mut base = signed.clone();
+        base.inputs[1].partial_sigs.clear();
+        let mut other = signed.clone();
+        other.inputs[0].partial_sigs.clear();
+        assert_eq!(wallet.finalize_psbt(&mut base.clone()), Err(SigningError::SigningFailed));
+
+        wallet.combine_psbts(&mut base, [other]).unwrap();
+        assert_eq!(base.inputs[0].partial_sigs, signed.inputs[0].partial_sigs);
+        assert_eq!(base.inputs[1].partial_sigs, signed.inputs[1].partial_sigs);
+        assert!(wallet.finalize_psbt(&mut base).unwrap());
+    }
+
+    #[test]
+    fn combine_psbts_rejects_different_transaction() {
+        let mut wallet = new_wallet();
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(20_000), 1);
+        let build = |wallet: &mut Wallet<Keychain>, amount: u64| {
+            let (psbt, _) = wallet
+                .build_tx()
+                .add_recipient_script(external_spk(), Amount::from_sat(amount))
+                .finish()
+                .unwrap();
+            psbt
+        };
+        let mut base = build(&mut wallet, 10_000);
+        let mut other = build(&mut wallet, 15_000);
+        assert!(wallet.sign(&mut other).unwrap());
+
+        let before = base.clone();
+        assert_eq!(
+            wallet.combine_psbts(&mut base, [other]),
+            Err(SigningError::InvalidSignature)
+        );
+        assert_eq!(base, before);
+    }
+
+    #[cfg(feature = "rusqlite")]
+    #[test]
+    fn labels_round_trip_through_sqlite_and_bip329() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        assert!(Wallet::from_sqlite(&mut conn).unwrap().is_none());
+        let new_wallet = || {
+            let mut keyring = KeyRing::new(bitcoin::Network::Signet);
+            let (receive, _) = keyring.add_multipath_descriptor(WPKH_MULTIPATH);
+            (Wallet::new(keyring), receive)
+        };
+        let (mut wallet, receive) = new_wallet();
+        let outpoint = receive_confirmed(&mut wallet, receive, Amount::from_sat(10_000), 1);
+        let address_label = "\"rent\" \\ März\n\u{1f4b0}";
+        wallet.set_address_label(receive, 0, address_label.into());
+        wallet.set_tx_label(outpoint.txid, "salary".into());
+        wallet.persist_to_sqlite(&mut conn).unwrap();
+
+        let wallet = Wallet::from_sqlite(&mut conn).unwrap().unwrap();
+        assert_eq!(wallet.address_label(&receive, 0), Some(address_label));
+        assert_eq!(wallet.tx_label(outpoint.txid), Some("salary"));
+        let exported = wallet.export_labels();
+        assert_eq!(exported.lines().count(), 2);
+
+        let (mut imported, _) = new_wallet();
+        assert_eq!(imported.import_labels(&exported), Ok(2));
+        assert_eq!(imported.address_label(&receive, 0), Some(address_label));
+        assert_eq!(imported.tx_label(outpoint.txid), Some("salary"));
+        assert_eq!(imported.export_labels(), exported);
+        assert!(imported.staged().is_some());
+    }
+
+    #[test]
+    fn import_labels_skips_foreign_records_and_rejects_invalid_ones() {
+        let mut wallet = new_wallet();
+        let (_, address) = wallet.reveal_next_address(Keychain::External).unwrap();
+        let foreign = Address::from_script(&external_spk(), bitcoin::Network::Signet).unwrap();
+        let records = alloc::format!(
+            "{{\"type\": \"addr\", \"ref\": \"{}\", \"label\": \"caf\\u00e9 \\ud83d\\udcb0\"}}\n\
+             \n\
+             {{\"type\":\"addr\",\"ref\":\"{}\",\"label\":\"not ours\"}}\n\
+             {{\"type\":\"output\",\"ref\":\"{}:0\",\"label\":\"x\",\"spendable\":false}}\n\
+             {{\"type\":\"tx\",\"ref\":\"{}\"}}\n",
+            address,
+            foreign,
+            Txid::all_zeros(),
+            Txid::all_zeros(),
+        );
+        assert_eq!(wallet.import_labels(&records), Ok(1));
+        assert_eq!(wallet.address_label(&Keychain::External, 0), Some("café 💰"));
+
+        let invalid = alloc::format!(
+            "{{\"type\":\"tx\",\"ref\":\"{}\",\"label\":\"a\"}}\n{{\"type\":\"tx\"",
+            Txid::all_zeros()
+        );
+        assert_eq!(
+            wallet.import_labels(&invalid),
+            Err(LabelImportError::InvalidRecord { line: 2 })
+        );
+        assert_eq!(wallet.tx_label(Txid::all_zeros()), None);
+    }
+
+    #[test]
+    fn reset_chain_from_removes_blocks_above() {
+        let mut wallet = new_wallet();
+        for height in [1, 2, 3] {
+            insert_checkpoint(&mut wallet, height);
+        }
+
+        wallet.reset_chain_from(1).unwrap();
+        assert_eq!(wallet.local_chain().tip().block_id(), block_id(1));
+
+        assert_eq!(
+            wallet.reset_chain_from(0),
+            Err(WalletError::Chain(ChainError::CannotResetGenesis))
+        );
+        assert_eq!(wallet.local_chain().tip().block_id(), block_id(1));
+    }
+
+    #[test]
+    fn apply_evicted_txs_replaced_by_fee() {
+        let mut wallet = new_wallet();
+        let outpoint =
+            receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(100_000), 1);
+        let original = spending_tx(&[outpoint], external_spk(), Amount::from_sat(99_000));
+        let replacement = spending_tx(&[outpoint], external_spk(), Amount::from_sat(98_000));
+        let (original_txid, replacement_txid) =
+            (original.compute_txid(), replacement.compute_txid());
+
+        wallet.apply_unconfirmed_txs([(Arc::new(original), 10)]);
+        assert_eq!(wallet.balance().total(), Amount::ZERO);
+
+        // the replacement is seen and the original leaves the mempool
+        wallet.apply_unconfirmed_txs([(Arc::new(replacement), 20)]);
+        wallet.apply_evicted_txs([(original_txid, 20)]);
+        assert!(wallet.tx_chain_position(original_txid).is_none());
+        assert!(wallet.tx_chain_position(replacement_txid).is_some());
+        assert_eq!(wallet.balance().total(), Amount::ZERO);
+
+        // once the replacement is evicted too, the output is unspent again
+        wallet.apply_evicted_txs([(replacement_txid, 30)]);
+        assert!(wallet.tx_chain_position(original_txid).is_none());
+        assert!(wallet.tx_chain_position(replacement_txid).is_none());
+        assert_eq!(wallet.balance().confirmed, Amount::from_sat(100_000));
+        let staged = wallet.staged().unwrap();
+        assert_eq!(staged.tx_graph.last_evicted.get(&original_txid), Some(&20));
+        assert_eq!(staged.tx_graph.last_evicted.get(&replacement_txid), Some(&30));
+
+        // evictions of unknown transactions are ignored
+        let unknown = Txid::all_zeros();
+        wallet.apply_evicted_txs([(unknown, 40)]);
+        assert!(!wallet.staged().unwrap().tx_graph.last_evicted.contains_key(&unknown));
+    }
+
+    #[test]
+    fn sign_after_reload_with_keymap() {
+        let mut wallet = new_wallet();
+        receive_confirmed(&mut wallet, Keychain::External, Amount::from_sat(100_000), 1);
+        let changeset = wallet.take_staged().unwrap();
+
+        let mut wallet = Wallet::from_changeset(changeset).unwrap();
+        assert!(wallet.keyring().is_watch_only());
+        let recipient = Address::from_script(&external_spk(), bitcoin::Network::Signet).unwrap();
+        let (mut psbt, _) = wallet
+            .build_tx()
+            .add_recipient(recipient.into_unchecked(), Amount::from_sat(10_000))
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert_eq!(wallet.sign(&mut psbt), Err(SigningError::MissingPrivateKey));
+
+        wallet.add_keymap(Keychain::External, WPKH_RECEIVE).unwrap();
+        assert!(wallet.keyring().has_secret(&Keychain::External));
+        assert!(wallet.sign(&mut psbt).unwrap());
+        assert!(wallet.finalize_psbt(&mut psbt).unwrap());
+        wallet.extract_tx(psbt).unwrap();
+    }
+}